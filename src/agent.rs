@@ -1,42 +1,59 @@
 use std::{
     collections::{HashMap, HashSet},
-    fmt::Debug,
+    fmt::{Debug, Display},
     sync::Arc,
     time::Duration,
 };
 
 use anyhow::{anyhow, bail, Context, Result};
+use ckb_jsonrpc_types::Script;
 use fnn::{
-    fiber::types::{Hash256, Pubkey},
+    fiber::{
+        serde_utils::U128Hex,
+        types::{Hash256, Pubkey},
+    },
     rpc::{
         channel::{Channel, OpenChannelParams},
         graph::NodeInfo,
         peer::{MultiAddr, PeerId},
     },
 };
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use crate::{
+    budget::{BudgetCoordinator, Reservation},
     config::{AgentConfig, TokenType},
     graph::Graph,
-    traits::GraphSource,
-    utils::{choice_n, get_peer_id_from_addr},
+    heuristics::HeuristicScore,
+    store::{AgentState, AttemptOutcome, PendingChannel, Store},
+    traits::{GraphSource, HeuristicContext},
+    utils::{conv, get_peer_id_from_addr, unix_timestamp},
 };
 
-// TODO: Remove after upgrade ckb_json_type to the same version
-macro_rules! conv {
-    ( $x:expr ) => {{
-        let v = serde_json::to_value($x).expect("conv");
-        serde_json::from_value(v).expect("conv")
-    }};
+/// A channel to open
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenChannelCmd {
+    #[serde_as(as = "DisplayFromStr")]
+    pub peer: PeerId,
+    #[serde_as(as = "U128Hex")]
+    pub funds: u128,
+    pub token: TokenType,
+    pub addresses: Vec<MultiAddr>,
+    /// Combined score of the peer
+    pub score: f64,
+    /// Score of each heuristic, empty for external nodes
+    #[serde(default)]
+    pub scores: Vec<HeuristicScore>,
 }
 
-#[derive(Debug, Clone)]
-struct OpenChannelCmd {
-    peer: PeerId,
-    funds: u128,
-    token: TokenType,
-    addresses: Vec<MultiAddr>,
+struct Network {
+    num: usize,
+    graph: Arc<Graph>,
+    local_channels: Vec<Channel>,
 }
 
 /// Autopilot agent
@@ -44,9 +61,14 @@ pub struct Agent<GS> {
     pub name: String,
     /// The id of the autopilot node
     pub self_id: Pubkey,
+    /// Funds are queried from the default funding lock of the node
+    pub funding_lock: Script,
     pub config: AgentConfig,
-    pub pending: HashSet<PeerId>,
+    pub state: AgentState,
+    pub store: Store,
     pub source: GS,
+    /// Reservations shared with agents funded by the same wallet
+    pub budget: BudgetCoordinator,
 }
 
 impl<GS> Debug for Agent<GS> {
@@ -59,21 +81,94 @@ impl<GS> Debug for Agent<GS> {
 }
 
 impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
-    pub fn new(name: String, self_id: Pubkey, config: AgentConfig, source: GS) -> Self {
+    pub fn new(
+        name: String,
+        self_id: Pubkey,
+        funding_lock: Script,
+        config: AgentConfig,
+        source: GS,
+        store: Store,
+    ) -> Self {
+        let mut state = store.load(&name);
+        state.token = Some(config.token.clone());
         Agent {
             name,
             self_id,
+            funding_lock,
             config,
-            pending: Default::default(),
+            state,
+            store,
             source,
+            budget: BudgetCoordinator::default(),
+        }
+    }
+
+    /// Share reservations with other agents, restored pending opens are reserved again
+    pub fn with_budget(mut self, budget: BudgetCoordinator) -> Self {
+        for (peer, p) in &self.state.pending {
+            budget.reserve(&self.name, peer.clone(), self.reservation(p.funds));
         }
+        self.budget = budget;
+        self
+    }
+
+    fn reservation(&self, funds: u128) -> Reservation {
+        let ckb = match self.config.token {
+            TokenType::Ckb => 0,
+            TokenType::Udt { .. } => self.config.budget.ckb_per_open,
+        };
+        Reservation {
+            pool: self.config.token.script().cloned(),
+            amount: funds,
+            ckb,
+        }
+    }
+
+    /// Finish a pending open and release its reservation
+    fn finish(
+        &mut self,
+        peer: &PeerId,
+        outcome: AttemptOutcome,
+        now: u64,
+    ) -> Option<PendingChannel> {
+        self.budget.release(&self.name, peer);
+        self.state.finish(peer, outcome, now)
     }
 
     #[instrument]
-    pub async fn setup(name: String, config: AgentConfig, source: GS) -> Result<Self> {
+    pub async fn setup(
+        name: String,
+        config: AgentConfig,
+        source: GS,
+        store: Store,
+    ) -> Result<Self> {
+        // fail early on unknown heuristics or bad params
+        crate::heuristics::validate(&config.heuristics)?;
+        config.selection.strategy.validate()?;
+        config.allocation.validate()?;
+        config.sizing.validate()?;
+        config.reserve.validate()?;
+        config.budget.validate()?;
+        // a state of another token means the agent was renamed or its token changed
+        if let Some(token) = store.load(&name).token {
+            if !config.token.is_token(token.script().cloned()) {
+                bail!(
+                    "State of agent {name} belongs to token {}, not {}",
+                    token.name(),
+                    config.token.name()
+                );
+            }
+        }
         let node_info = source.node_info().await?;
         let self_id = node_info.node_id;
-        Ok(Self::new(name, self_id, config, source))
+        let funding_lock = conv!(node_info.default_funding_lock_script);
+        let agent = Self::new(name, self_id, funding_lock, config, source, store);
+        info!(
+            "Restore agent state pendings {} history {}",
+            agent.state.pending.len(),
+            agent.state.history.len()
+        );
+        Ok(agent)
     }
 
     #[instrument]
@@ -104,6 +199,114 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
     }
 
     pub async fn run_once(&mut self) -> Result<()> {
+        let Network {
+            num,
+            graph,
+            local_channels,
+        } = self.query_network().await?;
+        self.reconcile_pending(&local_channels).await;
+        let available_funds = self.query_available_funds().await?;
+        if self.config.prune.enabled {
+            self.prune_channels(&graph, &local_channels).await;
+        }
+        self.open_channels(available_funds, num, graph, local_channels)
+            .await
+    }
+
+    /// Select channels to open like `run_once` without opening them, the node and the store
+    /// are left untouched
+    pub async fn recommend(&mut self) -> Result<Vec<OpenChannelCmd>> {
+        let Network {
+            num,
+            graph,
+            local_channels,
+        } = self.query_network().await?;
+        // settle pending opens on a copy of the state, nothing is abandoned or saved
+        let state = self.state.clone();
+        self.settle_pending(&local_channels);
+        let local_peers = self.local_peers(&local_channels);
+        let selected = match self.query_available_funds().await {
+            Ok(available_funds) => {
+                self.select_channels(available_funds, num, graph, local_peers)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        self.state = state;
+        let cmds = selected?;
+        // later agents of the plan must not count the same funds
+        for cmd in &cmds {
+            self.budget
+                .reserve(&self.name, cmd.peer.clone(), self.reservation(cmd.funds));
+        }
+        Ok(cmds)
+    }
+
+    /// Open channels of a saved plan, funds and pending limits are checked again before each open
+    pub async fn apply(&mut self, cmds: Vec<OpenChannelCmd>) -> Result<()> {
+        let local_channels = self.source.local_channels().await?;
+        self.reconcile_pending(&local_channels).await;
+        let mut available_funds = self.query_available_funds().await?;
+        let ckb_opens = self.query_ckb_opens().await?.unwrap_or(usize::MAX);
+        let local_peers = self.local_peers(&local_channels);
+
+        info!(
+            "Apply plan token {} channels {} available_funds {available_funds} pendings {}",
+            self.config.token.name(),
+            cmds.len(),
+            self.state.pending.len()
+        );
+
+        let mut candidates: Vec<OpenChannelCmd> = Vec::default();
+        for cmd in cmds {
+            let peer = &cmd.peer;
+            if !self.config.token.is_token(cmd.token.script().cloned()) {
+                warn!(
+                    "Skipping {peer:?} since plan token {} mismatch agent token {}",
+                    cmd.token.name(),
+                    self.config.token.name()
+                );
+                continue;
+            }
+            if local_peers.contains(peer) || self.state.pending.contains_key(peer) {
+                info!("Skipping {peer:?} since channel is already opened or pending");
+                continue;
+            }
+            if self.state.pending.len() + candidates.len() >= self.config.max_pending {
+                warn!(
+                    "Stop applying plan since we had too many pending channels {} max_pending {}",
+                    self.state.pending.len() + candidates.len(),
+                    self.config.max_pending
+                );
+                break;
+            }
+            if candidates.len() >= ckb_opens {
+                warn!("Stop applying plan since the CKB balance covers {ckb_opens} opens");
+                break;
+            }
+            let open_fee = self.config.reserve.open_fee;
+            if cmd.funds < self.config.min_chan_funds
+                || cmd.funds.saturating_add(open_fee) > available_funds
+            {
+                warn!(
+                    "Skipping {peer:?} funds {} {} open fee {open_fee} available {} required {}",
+                    cmd.funds,
+                    self.config.token.name(),
+                    available_funds,
+                    self.config.min_chan_funds
+                );
+                continue;
+            }
+            available_funds -= cmd.funds + open_fee;
+            candidates.push(cmd);
+        }
+
+        self.execute_channels(candidates).await;
+        Ok(())
+    }
+
+    /// Query the graph and local channels, nothing is changed on the node or in the store
+    async fn query_network(&self) -> Result<Network> {
         let nodes = self.source.graph_nodes().await?;
         for n in &nodes {
             trace!(
@@ -141,51 +344,115 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
             channels.len(),
             local_channels.len()
         );
-        let graph = Arc::new(Graph::build(nodes, channels));
-
-        // query available funds
-        let self_node = self.source.node_info().await?;
-        let lock = conv!(self_node.default_funding_lock_script);
-        let available_funds = self
-            .source
-            .get_balance(lock, self.config.token.clone())
-            .await?;
-        let num = (self
+        let graph = Arc::new(Graph::build_view(
+            nodes,
+            channels,
+            &self.config.token,
+            self.config.graph_view,
+        ));
+
+        let mut num = (self
             .config
             .max_chan_num
             .saturating_sub(local_channels.len()))
         .min(20);
-        self.open_channels(available_funds, num, graph, local_channels)
-            .await
+        if let Some(opens) = self.query_ckb_opens().await? {
+            num = num.min(opens);
+        }
+        Ok(Network {
+            num,
+            graph,
+            local_channels,
+        })
+    }
+
+    pub(crate) async fn query_available_funds(&self) -> Result<u128> {
+        let balance = self
+            .source
+            .get_balance(self.funding_lock.clone(), self.config.token.clone())
+            .await?;
+        let reserved = self.config.reserve.reserved(balance.total);
+        // funds of unsettled opens are still in the balance
+        let pending = self.state.pending_funds();
+        let spendable = (balance.total - reserved).saturating_sub(pending);
+
+        // funds other agents reserved are still in the balance
+        let pool = self.config.token.script();
+        let others = self.budget.reserved_by_others(&self.name, pool);
+        let mut available = spendable.saturating_sub(others);
+        // the limit covers own reservations, which include pending opens
+        if let Some(limit) = self.config.budget.limit(balance.total - reserved) {
+            let own = self.budget.reserved_by(&self.name, pool);
+            available = available.min(limit.saturating_sub(own));
+        }
+        info!(
+            "Spendable {available} {} balance {} reserved {reserved} pending {pending} by others {others} open fee {} cells {:?} largest cell {:?}",
+            self.config.token.name(),
+            balance.total,
+            self.config.reserve.open_fee,
+            balance.cell_count,
+            balance.largest_cell
+        );
+        Ok(available)
+    }
+
+    /// Opens the CKB balance can still cover for a UDT agent, `None` if unlimited
+    async fn query_ckb_opens(&self) -> Result<Option<usize>> {
+        let ckb_per_open = self.config.budget.ckb_per_open;
+        if matches!(self.config.token, TokenType::Ckb) || ckb_per_open == 0 {
+            return Ok(None);
+        }
+        let balance = self
+            .source
+            .get_balance(self.funding_lock.clone(), TokenType::Ckb)
+            .await?;
+        let reserved = self.budget.reserved_by_others(&self.name, None)
+            + self.budget.reserved_by(&self.name, None);
+        let available = balance.total.saturating_sub(reserved);
+        let opens = (available / ckb_per_open).try_into().unwrap_or(usize::MAX);
+        info!(
+            "CKB balance {} reserved {reserved} covers {opens} opens of {}",
+            balance.total,
+            self.config.token.name()
+        );
+        Ok(Some(opens))
     }
 
     pub(crate) async fn open_channels(
         &mut self,
-        mut available_funds: u128,
+        available_funds: u128,
         num: usize,
         graph: Arc<Graph>,
         local_channels: Vec<Channel>,
     ) -> Result<()> {
+        let local_peers = self.local_peers(&local_channels);
+        let candidates = self
+            .select_channels(available_funds, num, graph, local_peers)
+            .await?;
+        self.execute_channels(candidates).await;
+        Ok(())
+    }
+
+    /// Select candidates to open, peers in `local_peers` and pending are ignored
+    pub(crate) async fn select_channels(
+        &mut self,
+        mut available_funds: u128,
+        num: usize,
+        graph: Arc<Graph>,
+        local_peers: HashSet<PeerId>,
+    ) -> Result<Vec<OpenChannelCmd>> {
         info!(
-            "Open channels token {} available_funds {available_funds:?} num {num:?} local channels {} pendings {}",
+            "Select channels token {} available_funds {available_funds:?} num {num:?} local peers {} pendings {}",
             self.config.token.name(),
-            local_channels.len(),self.pending.len()
+            local_peers.len(),self.state.pending.len()
         );
-        // check connected pending channels
-        for c in local_channels.iter() {
-            if self.pending.remove(&c.peer_id) {
-                info!(
-                    "Successfully open channel {:?} {:?} with {:?} funds {} {}",
-                    c.channel_id,
-                    c.channel_outpoint,
-                    c.peer_id,
-                    c.local_balance,
-                    self.config.token.name()
-                );
-            }
-        }
 
-        let chan_funds = self.config.max_chan_funds.min(available_funds);
+        // each open also pays the estimated fee
+        let open_fee = self.config.reserve.open_fee;
+        let chan_funds = self
+            .config
+            .max_chan_funds
+            .min(available_funds.saturating_sub(open_fee));
         if chan_funds < self.config.min_chan_funds {
             bail!(
                 "Not enough funds to open channel, token {} available {} required {}",
@@ -195,39 +462,33 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
             );
         }
 
-        if self.pending.len() >= self.config.max_pending {
+        if self.state.pending.len() >= self.config.max_pending {
             debug!(
                 "Stop open connections since we had too many pending channels {} max_pending {}",
-                self.pending.len(),
+                self.state.pending.len(),
                 self.config.max_pending
             );
-            return Ok(());
+            return Ok(Vec::default());
         }
 
         // open channels up to max_pending
-        // TODO: We should stop open channel and remove peer from pending after timeout
-        let num = num.min(self.config.max_pending - self.pending.len());
+        let num = num.min(self.config.max_pending - self.state.pending.len());
 
-        let mut ignored: HashSet<PeerId> = local_channels
-            .into_iter()
-            .filter_map(|c| {
-                // only ignore same token local channels
-                if self
-                    .config
-                    .token
-                    .is_token(c.funding_udt_type_script.map(|s| conv!(s)))
-                {
-                    Some(c.peer_id)
-                } else {
-                    None
-                }
-            })
-            .chain(self.pending.clone().into_iter())
+        let mut ignored: HashSet<PeerId> = local_peers
+            .iter()
+            .cloned()
+            .chain(self.state.pending.keys().cloned())
+            .chain(
+                self.state
+                    .failed_peers(unix_timestamp().saturating_sub(self.config.retry_backoff))
+                    .cloned(),
+            )
             .collect();
         ignored.insert(PeerId::from_public_key(&self.self_id.into()));
 
         let mut nodes: HashSet<PeerId> = HashSet::default();
         let mut addresses: HashMap<PeerId, Vec<MultiAddr>> = HashMap::default();
+        let mut min_funding: HashMap<PeerId, u128> = HashMap::default();
 
         for node in graph.nodes() {
             // skip ignored
@@ -261,6 +522,7 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
                 continue;
             }
 
+            min_funding.insert(peer.clone(), min_funding_amount);
             // store addresses
             addresses
                 .entry(peer.clone())
@@ -269,10 +531,30 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
             nodes.insert(peer);
         }
 
+        // log the seed so picks of the round can be reproduced
+        let seed = self.config.selection.seed.unwrap_or_else(rand::random);
+        info!("Select candidates with seed {seed}");
+
+        let mut details: HashMap<PeerId, Vec<HeuristicScore>> = HashMap::default();
+        let context = HeuristicContext {
+            self_id: self.self_id,
+            token: self.config.token.clone(),
+            local_peers,
+            seed,
+            fixed_seed: self.config.selection.seed,
+        };
+        let capacity_factors =
+            self.config
+                .sizing
+                .capacity_factors(&graph, &self.config.token, &nodes);
         let mut scores: Vec<(PeerId, f64)> =
-            crate::heuristics::get_node_scores(&self.config.heuristics, graph, nodes)
+            crate::heuristics::get_node_scores(&self.config.heuristics, graph, nodes, &context)
                 .await?
                 .into_iter()
+                .map(|(peer, s)| {
+                    details.insert(peer.clone(), s.details);
+                    (peer, s.score)
+                })
                 .collect();
 
         // Insert external nodes scores
@@ -287,33 +569,60 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
             }
         }
 
+        // picks depend on the order of scores, not only the seed
+        scores.sort_by_cached_key(|(peer, _)| peer.to_string());
         debug!("Get {} scores", scores.len());
         for (peer, s) in scores.iter() {
             trace!("{peer:?} {s}");
         }
         let mut candidates: Vec<OpenChannelCmd> = Vec::default();
 
-        for (peer, _) in choice_n(scores, num) {
-            let chan_funds = available_funds.min(chan_funds);
-            available_funds -= chan_funds;
-
-            if chan_funds < self.config.min_chan_funds {
+        let all_scores: Vec<f64> = scores.iter().map(|(_, s)| *s).collect();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let picks = self.config.selection.strategy.select(scores, num, &mut rng);
+        let sizes = self.config.allocation.allocate(
+            &picks,
+            &all_scores,
+            available_funds.saturating_sub(open_fee.saturating_mul(picks.len() as u128)),
+            self.config.min_chan_funds,
+            self.config.max_chan_funds,
+        );
+        for ((peer, score), size) in picks.into_iter().zip(sizes) {
+            let Some(size) = size else {
+                trace!("Drop candidate {peer:?} left out by allocation");
+                continue;
+            };
+            let peer_min = min_funding.get(&peer).cloned().unwrap_or_default();
+            let size = self.config.sizing.size(
+                size,
+                capacity_factors.get(&peer).cloned().unwrap_or(1.0),
+                peer_min,
+                self.config.min_chan_funds,
+                self.config.max_chan_funds,
+            );
+            let chan_funds = available_funds.saturating_sub(open_fee).min(size);
+            let required = peer_min.max(self.config.min_chan_funds);
+            if chan_funds < required {
                 trace!(
-                    "Chan funds too small chan_funds {} required {} {}",
+                    "Drop candidate {peer:?} chan funds too small chan_funds {} required {} {}",
                     chan_funds,
-                    self.config.min_chan_funds,
+                    required,
                     self.config.token.name(),
                 );
-                break;
+                continue;
             }
+            available_funds -= chan_funds + open_fee;
 
             let addresses = addresses[&peer].clone();
             let token = self.config.token.clone();
+            let scores = details.remove(&peer).unwrap_or_default();
             let cmd = OpenChannelCmd {
                 peer,
                 funds: chan_funds,
                 token,
                 addresses,
+                score,
+                scores,
             };
             candidates.push(cmd);
         }
@@ -322,21 +631,35 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
             "Get {} candidates, query num {} pending {}/{}",
             candidates.len(),
             num,
-            self.pending.len(),
+            self.state.pending.len(),
             self.config.max_pending
         );
 
+        Ok(candidates)
+    }
+
+    /// Open channels with candidates
+    async fn execute_channels(&mut self, candidates: Vec<OpenChannelCmd>) {
         let mut handles = Vec::default();
 
         // start cmd
         for cmd in candidates {
             let peer = cmd.peer.clone();
-            if self.pending.contains(&peer) {
+            if self.state.pending.contains_key(&peer) {
                 info!("Skipping pending connection {:?}", peer);
                 continue;
             }
 
-            self.pending.insert(peer);
+            self.budget
+                .reserve(&self.name, peer.clone(), self.reservation(cmd.funds));
+            self.state.pending.insert(
+                peer,
+                PendingChannel {
+                    opened_at: unix_timestamp(),
+                    temporary_channel_id: None,
+                    funds: cmd.funds,
+                },
+            );
 
             let handle = tokio::spawn(Self::execute(cmd.clone(), self.source.clone()));
             handles.push((cmd, handle));
@@ -349,24 +672,253 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
                 funds,
                 addresses,
                 token,
+                ..
             } = cmd;
             match handle.await {
                 Ok(Ok(temp_channel_id)) => {
                     info!("Initial open channel {temp_channel_id:?} with {peer:?} {addresses:?} funds {funds} {}",token.name());
                     // We must wait for peer to accept the channel
+                    if let Some(p) = self.state.pending.get_mut(&peer) {
+                        p.temporary_channel_id = Some(temp_channel_id);
+                    }
                 }
                 Ok(Err(err)) => {
                     error!("Failed to open channel {peer:?} {addresses:?} {err:?}");
-                    self.pending.remove(&peer);
+                    let reason = format!("{err:#}");
+                    self.finish(&peer, AttemptOutcome::Failed { reason }, unix_timestamp());
                 }
                 Err(err) => {
                     error!("Failed to execute {peer:?} {addresses:?} {err:?}");
-                    self.pending.remove(&peer);
+                    let reason = err.to_string();
+                    self.finish(&peer, AttemptOutcome::Failed { reason }, unix_timestamp());
                 }
             }
         }
+        self.save_state();
+    }
 
-        Ok(())
+    /// Peers we already have channels with the agent's token
+    fn local_peers(&self, local_channels: &[Channel]) -> HashSet<PeerId> {
+        local_channels
+            .iter()
+            .filter(|c| {
+                self.config
+                    .token
+                    .is_token(c.funding_udt_type_script.as_ref().map(|s| conv!(s)))
+            })
+            .map(|c| c.peer_id.clone())
+            .collect()
+    }
+
+    /// Move opened channels out of pending and expire stale ones, reservations of finished
+    /// opens are released and expired opens are abandoned if `abandon_expired` is set
+    async fn reconcile_pending(&mut self, local_channels: &[Channel]) {
+        let pending: Vec<PeerId> = self.state.pending.keys().cloned().collect();
+        let expired = self.settle_pending(local_channels);
+        for peer in pending {
+            if !self.state.pending.contains_key(&peer) {
+                self.budget.release(&self.name, &peer);
+            }
+        }
+        if self.config.abandon_expired {
+            for (peer, p) in expired {
+                let Some(channel_id) = p.temporary_channel_id else {
+                    continue;
+                };
+                match self.source.abandon_channel(channel_id).await {
+                    Ok(()) => info!("Abandon expired channel {channel_id:?} with {peer:?}"),
+                    Err(err) => {
+                        error!("Failed to abandon expired channel {channel_id:?} with {peer:?} {err:?}")
+                    }
+                }
+            }
+        }
+        self.save_state();
+    }
+
+    /// Move opened channels out of pending and expire stale ones in memory,
+    /// returns expired opens
+    fn settle_pending(&mut self, local_channels: &[Channel]) -> Vec<(PeerId, PendingChannel)> {
+        // an open is settled once its funding transaction is confirmed, funds of unsettled
+        // opens are still counted in the balance
+        let mut opening: HashSet<PeerId> = HashSet::default();
+        for c in local_channels {
+            if !self
+                .config
+                .token
+                .is_token(c.funding_udt_type_script.as_ref().map(|s| conv!(s)))
+            {
+                continue;
+            }
+            if is_channel_opening(c) {
+                opening.insert(c.peer_id.clone());
+                continue;
+            }
+            if !is_channel_ready(c) {
+                continue;
+            }
+            let outcome = AttemptOutcome::Opened {
+                channel_id: c.channel_id,
+            };
+            if self
+                .state
+                .finish(&c.peer_id, outcome, unix_timestamp())
+                .is_some()
+            {
+                info!(
+                    "Successfully open channel {:?} {:?} with {:?} funds {} {}",
+                    c.channel_id,
+                    c.channel_outpoint,
+                    c.peer_id,
+                    c.local_balance,
+                    self.config.token.name()
+                );
+            }
+        }
+        // forget channels that are closed on the node
+        self.state
+            .opened
+            .retain(|id| local_channels.iter().any(|c| &c.channel_id == id));
+
+        self.expire_pending(&opening)
+    }
+
+    /// Close local channels whose peers are dead or departed, a channel is closed after it stays
+    /// prunable for `grace_period`, at most `max_close_per_round` channels are closed in a round.
+    /// Only channels opened by the agent are pruned.
+    async fn prune_channels(&mut self, graph: &Graph, local_channels: &[Channel]) {
+        let now = unix_timestamp();
+        let prune = self.config.prune.clone();
+        let nodes: HashMap<PeerId, &NodeInfo> = graph
+            .nodes()
+            .iter()
+            .map(|n| (PeerId::from_public_key(&n.node_id.into()), n))
+            .collect();
+        let mut prunable: Vec<(&Channel, PruneReason)> = Vec::default();
+        for c in local_channels {
+            if !self
+                .config
+                .token
+                .is_token(c.funding_udt_type_script.as_ref().map(|s| conv!(s)))
+                || !is_channel_ready(c)
+                || !self.state.opened.contains(&c.channel_id)
+            {
+                continue;
+            }
+            let reason = match nodes.get(&c.peer_id) {
+                None => PruneReason::Departed,
+                Some(node) if node.addresses.is_empty() => PruneReason::NoAddress,
+                Some(node) => {
+                    // announcement timestamp is in milliseconds
+                    let last_seen = node.timestamp / 1000;
+                    if now.saturating_sub(last_seen) < prune.unreachable_after {
+                        continue;
+                    }
+                    PruneReason::Unreachable { last_seen }
+                }
+            };
+            prunable.push((c, reason));
+        }
+
+        // forget channels that recovered or are no longer ready
+        self.state
+            .prune_flagged
+            .retain(|id, _| prunable.iter().any(|(c, _)| &c.channel_id == id));
+        for (c, reason) in &prunable {
+            self.state
+                .prune_flagged
+                .entry(c.channel_id)
+                .or_insert_with(|| {
+                    info!(
+                        "Flag channel {:?} with {:?} to prune, reason: {reason}",
+                        c.channel_id, c.peer_id
+                    );
+                    now
+                });
+        }
+
+        let expired: Vec<(&Channel, PruneReason)> = prunable
+            .into_iter()
+            .filter(|(c, _)| {
+                let flagged_at = self.state.prune_flagged[&c.channel_id];
+                now.saturating_sub(flagged_at) >= prune.grace_period
+            })
+            .collect();
+        if expired.len() > prune.max_close_per_round {
+            debug!(
+                "Defer closing {} channels since max_close_per_round {}",
+                expired.len() - prune.max_close_per_round,
+                prune.max_close_per_round
+            );
+        }
+        for (c, reason) in expired.into_iter().take(prune.max_close_per_round) {
+            match self
+                .source
+                .shutdown_channel(c.channel_id, prune.close_script.clone(), prune.fee_rate)
+                .await
+            {
+                Ok(()) => {
+                    info!(
+                        "Shutdown channel {:?} with {:?} funds {} {}, reason: {reason}",
+                        c.channel_id,
+                        c.peer_id,
+                        c.local_balance,
+                        self.config.token.name()
+                    );
+                    self.state.prune_flagged.remove(&c.channel_id);
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to shutdown channel {:?} with {:?} {err:?}",
+                        c.channel_id, c.peer_id
+                    )
+                }
+            }
+        }
+        self.save_state();
+    }
+
+    fn save_state(&self) {
+        if let Err(err) = self.store.save(&self.name, &self.state) {
+            error!("Failed to save agent state {err:?}");
+        }
+    }
+
+    /// Remove pending channels that exceeded `pending_timeout`, opens accepted by peers in
+    /// `opening` wait for their funding to confirm however long it takes
+    fn expire_pending(&mut self, opening: &HashSet<PeerId>) -> Vec<(PeerId, PendingChannel)> {
+        let now = unix_timestamp();
+        let expired: Vec<PeerId> = self
+            .state
+            .pending
+            .iter()
+            .filter(|(peer, p)| {
+                !opening.contains(*peer)
+                    && now.saturating_sub(p.opened_at) >= self.config.pending_timeout
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect();
+
+        let mut expired_channels = Vec::with_capacity(expired.len());
+        for peer in expired {
+            let Some(p) = self.state.finish(&peer, AttemptOutcome::Expired, now) else {
+                continue;
+            };
+            let reason = if p.temporary_channel_id.is_some() {
+                "peer did not accept the channel"
+            } else {
+                "open channel request did not return"
+            };
+            warn!(
+                "Expire pending channel {:?} with {peer:?} funds {} {} after {}s, reason: {reason}",
+                p.temporary_channel_id,
+                p.funds,
+                self.config.token.name(),
+                now.saturating_sub(p.opened_at),
+            );
+            expired_channels.push((peer, p));
+        }
+        expired_channels
     }
 
     async fn execute(cmd: OpenChannelCmd, source: GS) -> Result<Hash256> {
@@ -375,6 +927,7 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
             funds,
             addresses,
             token,
+            ..
         } = cmd;
 
         let address = addresses
@@ -412,6 +965,46 @@ impl<GS: GraphSource + Send + Clone + Debug + 'static> Agent<GS> {
     }
 }
 
+/// Why a local channel is prunable
+enum PruneReason {
+    /// Peer is not in the graph
+    Departed,
+    NoAddress,
+    /// Node announcement is older than `unreachable_after`
+    Unreachable {
+        last_seen: u64,
+    },
+}
+
+impl Display for PruneReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Departed => write!(f, "peer left the graph"),
+            Self::NoAddress => write!(f, "peer has no known addresses"),
+            Self::Unreachable { last_seen } => {
+                write!(f, "peer has not announced since {last_seen}")
+            }
+        }
+    }
+}
+
+/// Only ready channels can be closed cooperatively
+fn channel_state_name(channel: &Channel) -> Option<String> {
+    serde_json::to_value(&channel.state)
+        .ok()
+        .and_then(|state| state["state_name"].as_str().map(str::to_string))
+}
+
+fn is_channel_ready(channel: &Channel) -> bool {
+    channel_state_name(channel).is_some_and(|name| name == "CHANNEL_READY")
+}
+
+/// The peer accepted the channel and its funding is not confirmed yet
+fn is_channel_opening(channel: &Channel) -> bool {
+    channel_state_name(channel)
+        .is_some_and(|name| !matches!(name.as_str(), "CHANNEL_READY" | "SHUTTING_DOWN" | "CLOSED"))
+}
+
 fn get_min_funding_amount(token: &TokenType, node: &NodeInfo) -> Option<u128> {
     match token {
         TokenType::Ckb => Some(node.auto_accept_min_ckb_funding_amount as u128),
@@ -424,3 +1017,6 @@ fn get_min_funding_amount(token: &TokenType, node: &NodeInfo) -> Option<u128> {
         }),
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,751 @@
+use std::{fmt::Debug, path::Path};
+
+use fnn::fiber::types::Hash256;
+use serde_json::{json, Value};
+
+use super::Agent;
+use crate::{
+    budget::BudgetCoordinator,
+    config::AgentConfig,
+    graph_source::{
+        memory::{Call, MemoryGraphSource, Method},
+        snapshot::{RecordedAction, SnapshotGraphSource},
+    },
+    store::{AttemptOutcome, PendingChannel, Store},
+    testing::{
+        address, channel, funding_lock, hash, node, node_info, peer, pubkey, udt, CHAN_FUNDS,
+    },
+    traits::GraphSource,
+    utils::unix_timestamp,
+};
+
+const CONFIG: &str = r#"
+token.type = "Ckb"
+external_nodes = []
+interval = 1
+max_chan_num = 100
+max_pending = 20
+min_chan_funds = "0x64"
+max_chan_funds = "0x3e8"
+[[heuristics]]
+heuristic = "Random"
+weight = 1.0
+"#;
+
+fn channel_id(i: usize) -> Hash256 {
+    serde_json::from_value(json!(hash(100 + i))).expect("channel id")
+}
+
+fn local_channel(i: usize) -> Value {
+    json!({
+        "channel_id": hash(100 + i),
+        "is_public": true,
+        "channel_outpoint": format!("0x{:064x}00000000", 100 + i),
+        "peer_id": peer(i).to_string(),
+        "funding_udt_type_script": null,
+        "state": { "state_name": "CHANNEL_READY", "state_flags": [] },
+        "local_balance": format!("{CHAN_FUNDS:#x}"),
+        "offered_tlc_balance": "0x0",
+        "remote_balance": "0x0",
+        "received_tlc_balance": "0x0",
+        "latest_commitment_transaction_hash": null,
+        "created_at": "0x0",
+        "enabled": true,
+        "tlc_expiry_delta": "0x0",
+        "tlc_fee_proportional_millionths": "0x0",
+    })
+}
+
+/// The autopilot node and four candidates in a ring
+fn setup_graph(source: &MemoryGraphSource) {
+    let nodes = (0..5).map(node).collect();
+    let channels = vec![
+        channel(0, 0, 1),
+        channel(1, 1, 2),
+        channel(2, 2, 3),
+        channel(3, 3, 4),
+        channel(4, 4, 1),
+    ];
+    source.set_graph(nodes, channels);
+}
+
+/// Build an agent, `overrides` replaces keys of the default config
+fn agent<GS: GraphSource + Send + Clone + Debug + 'static>(
+    source: &GS,
+    overrides: &str,
+) -> Agent<GS> {
+    let mut config: toml::Table = toml::from_str(CONFIG).expect("config");
+    let overrides: toml::Table = toml::from_str(overrides).expect("overrides");
+    config.extend(overrides);
+    let config: AgentConfig = toml::Value::Table(config).try_into().expect("config");
+    Agent::new(
+        "agent-0".to_string(),
+        pubkey(0),
+        funding_lock(),
+        config,
+        source.clone(),
+        Store::in_memory(),
+    )
+}
+
+/// Record channels with `peers` as opened by the agent
+fn record_opened(agent: &mut Agent<MemoryGraphSource>, peers: impl IntoIterator<Item = usize>) {
+    for i in peers {
+        agent.state.pending.insert(
+            peer(i),
+            PendingChannel {
+                opened_at: 0,
+                temporary_channel_id: None,
+                funds: CHAN_FUNDS,
+            },
+        );
+        let outcome = AttemptOutcome::Opened {
+            channel_id: channel_id(i),
+        };
+        agent.state.finish(&peer(i), outcome, 0);
+    }
+}
+
+fn opened_funds(calls: &[Call]) -> Vec<u128> {
+    let mut funds: Vec<u128> = calls
+        .iter()
+        .filter_map(|c| match c {
+            Call::OpenChannel { funds, .. } => Some(*funds),
+            _ => None,
+        })
+        .collect();
+    funds.sort_unstable();
+    funds
+}
+
+fn opened_peers(calls: &[Call]) -> Vec<String> {
+    let mut peers: Vec<String> = calls
+        .iter()
+        .filter_map(|c| match c {
+            Call::OpenChannel { peer, .. } => Some(peer.to_string()),
+            _ => None,
+        })
+        .collect();
+    peers.sort_unstable();
+    peers
+}
+
+fn closed_channels(calls: &[Call]) -> Vec<String> {
+    calls
+        .iter()
+        .filter_map(|c| match c {
+            Call::ShutdownChannel { channel_id } => Some(format!("{channel_id:?}")),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_open_channels() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, "");
+
+    agent.run_once().await.expect("run once");
+    let expected: Vec<_> = (1..5).map(|i| (peer(i), CHAN_FUNDS)).collect();
+    source.assert_opened(&expected);
+    source.assert_calls(Method::ConnectPeer, 4);
+    assert_eq!(agent.state.pending.len(), 4);
+    assert!(agent
+        .state
+        .pending
+        .values()
+        .all(|p| p.temporary_channel_id.is_some()));
+    let calls = source.take_calls();
+    assert!(calls
+        .iter()
+        .any(|c| matches!(c, Call::GetBalance { token } if token == "ckb")));
+
+    // channels show up in the next round
+    source.set_local_channels((1..5).map(local_channel).collect());
+    agent.run_once().await.expect("run once");
+    source.assert_calls(Method::OpenChannel, 0);
+    assert!(agent.state.pending.is_empty());
+    assert_eq!(agent.state.history.len(), 4);
+    assert!(agent
+        .state
+        .history
+        .iter()
+        .all(|r| matches!(r.outcome, AttemptOutcome::Opened { .. })));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_ignore_local_and_pending_peers() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    source.set_local_channels(vec![local_channel(1)]);
+    let mut agent = agent(&source, "");
+    agent.state.pending.insert(
+        peer(2),
+        PendingChannel {
+            opened_at: unix_timestamp(),
+            temporary_channel_id: None,
+            funds: CHAN_FUNDS,
+        },
+    );
+
+    agent.run_once().await.expect("run once");
+    source.assert_opened(&[(peer(3), CHAN_FUNDS), (peer(4), CHAN_FUNDS)]);
+    assert_eq!(agent.state.pending.len(), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_skip_peers_require_high_funding() {
+    let source = MemoryGraphSource::default();
+    let mut nodes: Vec<Value> = (0..5).map(node).collect();
+    nodes[4]["auto_accept_min_ckb_funding_amount"] = json!(format!("{:#x}", CHAN_FUNDS + 1));
+    source.set_graph(nodes, vec![channel(0, 1, 2), channel(1, 3, 4)]);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, "");
+
+    agent.run_once().await.expect("run once");
+    let expected: Vec<_> = (1..4).map(|i| (peer(i), CHAN_FUNDS)).collect();
+    source.assert_opened(&expected);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_split_funds() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(2 * CHAN_FUNDS + CHAN_FUNDS / 2);
+    let mut agent = agent(&source, "");
+
+    agent.run_once().await.expect("run once");
+    let calls = source.take_calls();
+    assert_eq!(
+        opened_funds(&calls),
+        vec![CHAN_FUNDS / 2, CHAN_FUNDS, CHAN_FUNDS]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_even_split_drops_unfundable() {
+    let source = MemoryGraphSource::default();
+    let mut nodes: Vec<Value> = (0..5).map(node).collect();
+    nodes[4]["auto_accept_min_ckb_funding_amount"] = json!(format!("{:#x}", 900));
+    source.set_graph(nodes, vec![channel(0, 1, 2), channel(1, 3, 4)]);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(
+        &source,
+        "allocation = { type = \"EvenSplit\", budget = \"0x9c4\" }\nsizing = { honor_peer_min = false }",
+    );
+
+    // 2500 split by 4, peer 4 requires more and the rest are still opened
+    agent.run_once().await.expect("run once");
+    let expected: Vec<_> = (1..4).map(|i| (peer(i), 625)).collect();
+    source.assert_opened(&expected);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_honor_peer_min() {
+    let source = MemoryGraphSource::default();
+    let mut nodes: Vec<Value> = (0..5).map(node).collect();
+    nodes[4]["auto_accept_min_ckb_funding_amount"] = json!(format!("{:#x}", 900));
+    source.set_graph(nodes, vec![channel(0, 1, 2), channel(1, 3, 4)]);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(
+        &source,
+        "allocation = { type = \"EvenSplit\", budget = \"0x9c4\" }",
+    );
+
+    agent.run_once().await.expect("run once");
+    let mut expected: Vec<_> = (1..4).map(|i| (peer(i), 625)).collect();
+    expected.push((peer(4), 900));
+    source.assert_opened(&expected);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_capacity_sizing() {
+    let source = MemoryGraphSource::default();
+    let mut hub = channel(2, 2, 3);
+    hub["capacity"] = json!(format!("{:#x}", 4 * CHAN_FUNDS));
+    let channels = vec![
+        channel(0, 0, 1),
+        channel(1, 1, 2),
+        hub,
+        channel(3, 3, 4),
+        channel(4, 4, 1),
+    ];
+    source.set_graph((0..5).map(node).collect(), channels);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(
+        &source,
+        "max_chan_funds = \"0x7d0\"\nallocation = { type = \"EvenSplit\", budget = \"0x7d0\" }\nsizing = { capacity_scale = 4.0 }",
+    );
+
+    // the median of 1000 and 4000 of the hub peers is 2.5x the median of all channels
+    agent.run_once().await.expect("run once");
+    source.assert_opened(&[
+        (peer(1), CHAN_FUNDS / 2),
+        (peer(2), CHAN_FUNDS * 5 / 4),
+        (peer(3), CHAN_FUNDS * 5 / 4),
+        (peer(4), CHAN_FUNDS / 2),
+    ]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_reserve_funds() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(3 * CHAN_FUNDS + CHAN_FUNDS / 2);
+    let mut agent = agent(
+        &source,
+        "reserve = { amount = \"0x64\", percent = 20.0, open_fee = \"0x64\" }",
+    );
+
+    // 700 is reserved and each open pays 100
+    agent.run_once().await.expect("run once");
+    let calls = source.take_calls();
+    assert_eq!(opened_funds(&calls), vec![500, CHAN_FUNDS, CHAN_FUNDS]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_shared_budget() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(5 * CHAN_FUNDS);
+    let budget = BudgetCoordinator::default();
+    let mut first = agent(&source, "").with_budget(budget.clone());
+    let mut second = agent(&source, "").with_budget(budget.clone());
+    second.name = "agent-1".to_string();
+
+    // the balance still counts funds reserved by the first agent
+    first.run_once().await.expect("run once");
+    assert_eq!(opened_funds(&source.take_calls()), vec![CHAN_FUNDS; 4]);
+    second.run_once().await.expect("run once");
+    assert_eq!(opened_funds(&source.take_calls()), vec![CHAN_FUNDS]);
+
+    // expired opens release their reservations
+    first.config.pending_timeout = 0;
+    first.reconcile_pending(&[]).await;
+    assert_eq!(budget.reserved_by("agent-0", None), 0);
+    assert_eq!(budget.reserved_by_others("agent-0", None), CHAN_FUNDS);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_budget_cap() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, "budget = { cap = \"0x9c4\" }");
+
+    agent.run_once().await.expect("run once");
+    assert_eq!(
+        opened_funds(&source.take_calls()),
+        vec![CHAN_FUNDS / 2, CHAN_FUNDS, CHAN_FUNDS]
+    );
+    // reserved funds count toward the cap in later rounds
+    assert!(agent.run_once().await.is_err());
+    source.assert_calls(Method::OpenChannel, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_budget_share() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, "budget = { share = 0.5 }");
+
+    agent.run_once().await.expect("run once");
+    assert_eq!(opened_funds(&source.take_calls()), vec![CHAN_FUNDS; 4]);
+    // pending opens count toward the share once
+    assert_eq!(
+        agent.query_available_funds().await.expect("funds"),
+        CHAN_FUNDS
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pending_funds() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, "");
+
+    agent.run_once().await.expect("run once");
+    assert_eq!(agent.state.pending_funds(), 4 * CHAN_FUNDS);
+    assert_eq!(
+        agent.query_available_funds().await.expect("funds"),
+        6 * CHAN_FUNDS
+    );
+
+    // opens whose funding transaction is not confirmed stay pending
+    let channels: Vec<Value> = (1..5)
+        .map(|i| {
+            let mut c = local_channel(i);
+            if i > 2 {
+                c["state"] = json!({ "state_name": "NEGOTIATING_FUNDING", "state_flags": [] });
+            }
+            c
+        })
+        .collect();
+    source.set_local_channels(channels);
+    agent
+        .reconcile_pending(&source.local_channels().await.expect("channels"))
+        .await;
+    assert_eq!(agent.state.pending.len(), 2);
+    assert_eq!(
+        agent.query_available_funds().await.expect("funds"),
+        8 * CHAN_FUNDS
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_slow_funding() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let budget = BudgetCoordinator::default();
+    let mut agent =
+        agent(&source, "pending_timeout = 0\nabandon_expired = true").with_budget(budget.clone());
+
+    agent.run_once().await.expect("run once");
+    assert_eq!(agent.state.pending.len(), 4);
+    source.take_calls();
+
+    // accepted opens outlive pending_timeout while their funding confirms
+    let channels: Vec<Value> = (1..5)
+        .map(|i| {
+            let mut c = local_channel(i);
+            c["state"] = json!({ "state_name": "AWAITING_CHANNEL_READY", "state_flags": [] });
+            c
+        })
+        .collect();
+    source.set_local_channels(channels);
+    agent
+        .reconcile_pending(&source.local_channels().await.expect("channels"))
+        .await;
+    assert_eq!(agent.state.pending.len(), 4);
+    assert_eq!(budget.reserved_by("agent-0", None), 4 * CHAN_FUNDS);
+    source.assert_calls(Method::AbandonChannel, 0);
+
+    // and are recorded as opened once ready
+    source.set_local_channels((1..5).map(local_channel).collect());
+    agent
+        .reconcile_pending(&source.local_channels().await.expect("channels"))
+        .await;
+    assert!(agent.state.pending.is_empty());
+    assert_eq!(budget.reserved_by("agent-0", None), 0);
+    assert!(agent
+        .state
+        .history
+        .iter()
+        .all(|r| matches!(r.outcome, AttemptOutcome::Opened { .. })));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_setup_restore() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set(Method::NodeInfo, node_info());
+    source.set_balance(5 * CHAN_FUNDS);
+    let store = Store::in_memory();
+    let mut first = agent(&source, "");
+    first.store = store.clone();
+    first.run_once().await.expect("run once");
+
+    // a restarted agent restores pending opens and reserves their funds again
+    let budget = BudgetCoordinator::default();
+    let restored = Agent::setup(
+        first.name.clone(),
+        first.config.clone(),
+        source.clone(),
+        store.clone(),
+    )
+    .await
+    .expect("setup")
+    .with_budget(budget.clone());
+    let mut pending: Vec<String> = restored
+        .state
+        .pending
+        .keys()
+        .map(|p| p.to_string())
+        .collect();
+    pending.sort();
+    let mut expected: Vec<String> = (1..5).map(|i| peer(i).to_string()).collect();
+    expected.sort();
+    assert_eq!(pending, expected);
+    assert_eq!(budget.reserved_by(&first.name, None), 4 * CHAN_FUNDS);
+
+    // the state of another token is never restored
+    let mut state = store.load(&first.name);
+    state.token = Some(udt(9));
+    store.save(&first.name, &state).expect("save");
+    let restored = Agent::setup(first.name.clone(), first.config.clone(), source, store).await;
+    assert!(restored.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_replay_snapshot() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/snapshot");
+    let source = SnapshotGraphSource::load(&dir).expect("snapshot");
+    // the snapshot is a line 0 - 1 - 2 - 3 - 4 - 5, 2 and 3 are the most central
+    let overrides = r#"
+max_pending = 2
+selection = { type = "TopK", seed = 1 }
+[[heuristics]]
+heuristic = "Centrality"
+weight = 1.0
+"#;
+    let mut agent = agent(&source, overrides);
+
+    agent.run_once().await.expect("run once");
+    let mut opened: Vec<(String, u128)> = source
+        .actions()
+        .into_iter()
+        .filter_map(|a| match a {
+            RecordedAction::OpenChannel { peer, funds, .. } => Some((peer.to_string(), funds)),
+            _ => None,
+        })
+        .collect();
+    opened.sort();
+    let mut expected = vec![
+        (peer(2).to_string(), CHAN_FUNDS),
+        (peer(3).to_string(), CHAN_FUNDS),
+    ];
+    expected.sort();
+    assert_eq!(opened, expected);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_insufficient_funds() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(99);
+    let mut agent = agent(&source, "");
+
+    assert!(agent.run_once().await.is_err());
+    source.assert_calls(Method::ConnectPeer, 0);
+    source.assert_calls(Method::OpenChannel, 0);
+    assert!(agent.state.pending.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_connect_failure() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    source.fail_next(Method::ConnectPeer, "connection refused");
+    let mut agent = agent(&source, "");
+
+    agent.run_once().await.expect("run once");
+    source.assert_calls(Method::ConnectPeer, 4);
+    source.assert_calls(Method::OpenChannel, 3);
+    assert_eq!(agent.state.pending.len(), 3);
+    let record = &agent.state.history[0];
+    assert!(!agent.state.pending.contains_key(&record.peer));
+    assert!(
+        matches!(&record.outcome, AttemptOutcome::Failed { reason } if reason.contains("connection refused"))
+    );
+    source.take_calls();
+
+    // failed peer is not retried within retry_backoff
+    agent.run_once().await.expect("run once");
+    source.assert_calls(Method::OpenChannel, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_open_failure() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    source.fail_next(Method::OpenChannel, "peer rejected");
+    let mut agent = agent(&source, "");
+
+    agent.run_once().await.expect("run once");
+    source.assert_calls(Method::ConnectPeer, 4);
+    source.assert_calls(Method::OpenChannel, 4);
+    assert_eq!(agent.state.pending.len(), 3);
+    assert_eq!(agent.state.history.len(), 1);
+    assert!(matches!(
+        agent.state.history[0].outcome,
+        AttemptOutcome::Failed { .. }
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_external_nodes() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, &format!("external_nodes = [\"{}\"]", address(5)));
+
+    agent.run_once().await.expect("run once");
+    let expected: Vec<_> = (1..6).map(|i| (peer(i), CHAN_FUNDS)).collect();
+    source.assert_opened(&expected);
+    let calls = source.take_calls();
+    assert!(calls.iter().any(
+        |c| matches!(c, Call::ConnectPeer { address: addr } if addr.to_string() == address(5))
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_selection_seed() {
+    let overrides = "max_pending = 2\nselection = { type = \"Weighted\", seed = 7 }";
+    let mut picks = Vec::default();
+    for _ in 0..3 {
+        let source = MemoryGraphSource::default();
+        setup_graph(&source);
+        source.set_balance(10 * CHAN_FUNDS);
+        let mut agent = agent(&source, overrides);
+        agent.run_once().await.expect("run once");
+        picks.push(opened_peers(&source.take_calls()));
+    }
+    // random scores and weighted picks are reproduced by the seed
+    assert_eq!(picks[0].len(), 2);
+    assert!(picks.iter().all(|p| p == &picks[0]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_expire_pending() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, "pending_timeout = 0\nabandon_expired = true");
+
+    agent.run_once().await.expect("run once");
+    let mut temporary_channel_ids: Vec<String> = agent
+        .state
+        .pending
+        .values()
+        .filter_map(|p| p.temporary_channel_id.as_ref().map(|id| format!("{id:?}")))
+        .collect();
+    assert_eq!(temporary_channel_ids.len(), 4);
+    source.take_calls();
+
+    // expired peers are abandoned and not retried
+    agent.run_once().await.expect("run once");
+    source.assert_calls(Method::OpenChannel, 0);
+    let mut abandoned: Vec<String> = source
+        .take_calls()
+        .iter()
+        .filter_map(|c| match c {
+            Call::AbandonChannel { channel_id } => Some(format!("{channel_id:?}")),
+            _ => None,
+        })
+        .collect();
+    temporary_channel_ids.sort();
+    abandoned.sort();
+    assert_eq!(abandoned, temporary_channel_ids);
+    assert!(agent.state.pending.is_empty());
+    assert!(agent
+        .state
+        .history
+        .iter()
+        .all(|r| matches!(r.outcome, AttemptOutcome::Expired)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_recommend_read_only() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_balance(10 * CHAN_FUNDS);
+    let mut agent = agent(&source, "pending_timeout = 0\nabandon_expired = true");
+
+    agent.run_once().await.expect("run once");
+    assert_eq!(agent.state.pending.len(), 4);
+    source.take_calls();
+
+    // expired opens are neither abandoned nor dropped from the state
+    agent.recommend().await.expect("recommend");
+    let calls = source.take_calls();
+    assert!(!calls
+        .iter()
+        .any(|c| matches!(c, Call::AbandonChannel { .. } | Call::OpenChannel { .. })));
+    assert_eq!(agent.state.pending.len(), 4);
+    assert!(agent.state.history.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_prune_channels() {
+    let source = MemoryGraphSource::default();
+    let mut nodes: Vec<Value> = (0..5).map(node).collect();
+    // peer 2 has no addresses, peer 3 stopped announcing, peers 5 ~ 7 left the graph
+    nodes[2]["addresses"] = json!([]);
+    nodes[3]["timestamp"] = json!("0x0");
+    source.set_graph(nodes, vec![channel(0, 0, 1)]);
+    source.set_local_channels((1..8).map(local_channel).collect());
+    source.set_balance(0);
+    let mut agent = agent(
+        &source,
+        "[prune]\nenabled = true\ngrace_period = 0\nmax_close_per_round = 3",
+    );
+    // the channel with peer 7 was not opened by the agent
+    record_opened(&mut agent, 1..7);
+
+    assert!(agent.run_once().await.is_err());
+    let mut closed = closed_channels(&source.take_calls());
+    assert_eq!(closed.len(), 3);
+    assert_eq!(agent.state.prune_flagged.len(), 1);
+
+    // closed channels are shutting down, the rest is closed in the next round
+    let channels = (1..8)
+        .map(|i| {
+            let mut c = local_channel(i);
+            if closed.contains(&format!("{:?}", channel_id(i))) {
+                c["state"] = json!({ "state_name": "SHUTTING_DOWN", "state_flags": [] });
+            }
+            c
+        })
+        .collect();
+    source.set_local_channels(channels);
+    assert!(agent.run_once().await.is_err());
+    closed.extend(closed_channels(&source.take_calls()));
+    closed.sort();
+    let mut expected: Vec<String> = [2, 3, 5, 6]
+        .iter()
+        .map(|i| format!("{:?}", channel_id(*i)))
+        .collect();
+    expected.sort();
+    assert_eq!(closed, expected);
+    assert!(agent.state.prune_flagged.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_prune_truncated_history() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    // peer 5 left the graph
+    source.set_local_channels(vec![local_channel(5)]);
+    source.set_balance(0);
+    let mut agent = agent(&source, "[prune]\nenabled = true\ngrace_period = 0");
+    record_opened(&mut agent, [5]);
+    // the open dropped out of the history after later attempts
+    agent.state.history.clear();
+
+    assert!(agent.run_once().await.is_err());
+    let closed = closed_channels(&source.take_calls());
+    assert_eq!(closed, vec![format!("{:?}", channel_id(5))]);
+
+    // closed channels are forgotten
+    source.set_local_channels(vec![]);
+    assert!(agent.run_once().await.is_err());
+    assert!(agent.state.opened.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_prune_grace_period() {
+    let source = MemoryGraphSource::default();
+    setup_graph(&source);
+    source.set_local_channels(vec![local_channel(5)]);
+    source.set_balance(0);
+    let mut agent = agent(&source, "[prune]\nenabled = true\ngrace_period = 3600");
+    record_opened(&mut agent, [5]);
+
+    assert!(agent.run_once().await.is_err());
+    source.assert_calls(Method::ShutdownChannel, 0);
+    assert_eq!(agent.state.prune_flagged.len(), 1);
+
+    // the peer is back before the grace period ends
+    source.set_graph((0..6).map(node).collect(), vec![channel(0, 0, 5)]);
+    assert!(agent.run_once().await.is_err());
+    source.assert_calls(Method::ShutdownChannel, 0);
+    assert!(agent.state.prune_flagged.is_empty());
+}
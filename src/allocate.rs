@@ -0,0 +1,265 @@
+//! Size channels of selected candidates and keep reserved funds out of them
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use fnn::rpc::peer::PeerId;
+
+use crate::{
+    config::{Allocation, ReserveConfig, SizingConfig, TokenType},
+    graph::Graph,
+    utils::conv,
+};
+
+impl Allocation {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Self::Fixed | Self::Proportional => {}
+            Self::Tiered { tiers } => {
+                if let Some(tier) = tiers.iter().find(|t| !(0.0..=1.0).contains(&t.percentile)) {
+                    bail!(
+                        "percentile of tiers must be within 0.0 ~ 1.0, got {}",
+                        tier.percentile
+                    );
+                }
+            }
+            Self::EvenSplit { budget } => {
+                if *budget == 0 {
+                    bail!("budget of EvenSplit must be positive");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Funds of each of `picks`, `None` drops the candidate.
+    /// `scores` are scores of all candidates of the round, `available` the funds to spend.
+    pub fn allocate<T>(
+        &self,
+        picks: &[(T, f64)],
+        scores: &[f64],
+        available: u128,
+        min: u128,
+        max: u128,
+    ) -> Vec<Option<u128>> {
+        let clamp = |funds: u128| Some(funds.clamp(min, max.max(min)));
+        match self {
+            Self::Fixed => picks.iter().map(|_| clamp(max)).collect(),
+            Self::Proportional => {
+                let total: f64 = picks.iter().map(|(_, s)| s.max(0.0)).sum();
+                picks
+                    .iter()
+                    .map(|(_, s)| {
+                        let share = if total > 0.0 {
+                            s.max(0.0) / total
+                        } else {
+                            1.0 / picks.len() as f64
+                        };
+                        clamp((available as f64 * share) as u128)
+                    })
+                    .collect()
+            }
+            Self::Tiered { tiers } => picks
+                .iter()
+                .map(|(_, s)| {
+                    let percentile = percentile(scores, *s);
+                    let funds = tiers
+                        .iter()
+                        .filter(|t| percentile >= t.percentile)
+                        .max_by(|a, b| a.percentile.total_cmp(&b.percentile))
+                        .map(|t| t.funds)
+                        .unwrap_or(min);
+                    clamp(funds)
+                })
+                .collect(),
+            Self::EvenSplit { budget } => {
+                let budget = (*budget).min(available);
+                // keep the best scored candidates that an even split can fund
+                let mut order: Vec<usize> = (0..picks.len()).collect();
+                order.sort_by(|a, b| picks[*b].1.total_cmp(&picks[*a].1));
+                let mut kept = picks.len();
+                while kept > 0 && budget / (kept as u128) < min {
+                    kept -= 1;
+                }
+                let mut sizes = vec![None; picks.len()];
+                for i in order.into_iter().take(kept) {
+                    sizes[i] = clamp(budget / kept as u128);
+                }
+                sizes
+            }
+        }
+    }
+}
+
+impl ReserveConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=100.0).contains(&self.percent) {
+            bail!(
+                "reserve percent must be within 0 ~ 100, got {}",
+                self.percent
+            );
+        }
+        Ok(())
+    }
+
+    /// Reserved amount of `balance`
+    pub fn reserved(&self, balance: u128) -> u128 {
+        let percent = (balance as f64 * self.percent / 100.0) as u128;
+        self.amount.max(percent).min(balance)
+    }
+}
+
+impl SizingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(scale) = self.capacity_scale {
+            if !scale.is_finite() || scale < 1.0 {
+                bail!("capacity_scale must be at least 1.0, got {scale}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Adjust `allocated` funds by the capacity `factor` and the `peer_min` of the candidate
+    pub fn size(&self, allocated: u128, factor: f64, peer_min: u128, min: u128, max: u128) -> u128 {
+        let mut funds = allocated;
+        if let Some(scale) = self.capacity_scale {
+            let factor = factor.clamp(1.0 / scale, scale);
+            funds = ((funds as f64 * factor) as u128).clamp(min, max.max(min));
+        }
+        if self.honor_peer_min && peer_min <= max {
+            funds = funds.max(peer_min);
+        }
+        funds
+    }
+
+    /// Median capacity of `token` channels of each of `peers` over the median of all `token`
+    /// channels, empty if capacities are not scaled. Channels of other tokens in the graph view
+    /// are skipped since their capacities are in other units
+    pub fn capacity_factors(
+        &self,
+        graph: &Graph,
+        token: &TokenType,
+        peers: &HashSet<PeerId>,
+    ) -> HashMap<PeerId, f64> {
+        if self.capacity_scale.is_none() {
+            return HashMap::default();
+        }
+        let mut all: Vec<u128> = Vec::with_capacity(graph.channels().len());
+        let mut capacities: HashMap<PeerId, Vec<u128>> = HashMap::default();
+        for c in graph.channels() {
+            if !token.is_token(c.udt_type_script.as_ref().map(|s| conv!(s))) {
+                continue;
+            }
+            all.push(c.capacity);
+            for n in [c.node1, c.node2] {
+                let peer = PeerId::from_public_key(&n.into());
+                if peers.contains(&peer) {
+                    capacities.entry(peer).or_default().push(c.capacity);
+                }
+            }
+        }
+        let typical = median(&mut all);
+        if typical == 0 {
+            return HashMap::default();
+        }
+        capacities
+            .into_iter()
+            .map(|(peer, mut c)| (peer, median(&mut c) as f64 / typical as f64))
+            .collect()
+    }
+}
+
+/// Median of `values`, the mean of the middle two for an even count
+fn median(values: &mut [u128]) -> u128 {
+    if values.is_empty() {
+        return 0;
+    }
+    values.sort_unstable();
+    // the middle two are the same element for an odd count
+    let low = values[(values.len() - 1) / 2];
+    let high = values[values.len() / 2];
+    low + (high - low) / 2
+}
+
+/// Fraction of `scores` below `score`
+fn percentile(scores: &[f64], score: f64) -> f64 {
+    if scores.len() <= 1 {
+        return 1.0;
+    }
+    let below = scores.iter().filter(|s| **s < score).count();
+    (below as f64 / (scores.len() - 1) as f64).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::median;
+    use crate::{
+        config::{Allocation, AllocationTier, SizingConfig, TokenType},
+        testing::{channel, graph, node, peer, token_channel, udt},
+    };
+
+    #[test]
+    fn test_allocation_strategies() {
+        let picks = vec![(1, 0.5), (2, 0.3), (3, 0.2)];
+        let scores = vec![0.5, 0.3, 0.2, 0.0];
+
+        assert_eq!(
+            Allocation::Fixed.allocate(&picks, &scores, 5000, 100, 1000),
+            vec![Some(1000); 3]
+        );
+        // shares are clamped to min ~ max
+        assert_eq!(
+            Allocation::Proportional.allocate(&picks, &scores, 2000, 100, 1000),
+            vec![Some(1000), Some(600), Some(400)]
+        );
+        let tiers = vec![
+            AllocationTier {
+                percentile: 0.9,
+                funds: 1000,
+            },
+            AllocationTier {
+                percentile: 0.5,
+                funds: 500,
+            },
+        ];
+        assert_eq!(
+            Allocation::Tiered { tiers }.allocate(&picks, &scores, 5000, 100, 1000),
+            vec![Some(1000), Some(500), Some(100)]
+        );
+        // the lowest scored is dropped to fund the others
+        assert_eq!(
+            Allocation::EvenSplit { budget: 250 }.allocate(&picks, &scores, 5000, 100, 1000),
+            vec![Some(125), Some(125), None]
+        );
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut []), 0);
+        assert_eq!(median(&mut [4000, 1000, 2000]), 2000);
+        assert_eq!(median(&mut [4000, 1000]), 2500);
+    }
+
+    #[test]
+    fn test_capacity_factors() {
+        // capacities of UDT channels in the graph view are in other units
+        let ckb = TokenType::Ckb;
+        let channels = vec![
+            channel(0, 1, 2),
+            channel(1, 2, 3),
+            token_channel(2, 3, 4, &ckb, 4000),
+            token_channel(3, 1, 4, &udt(1), 1_000_000),
+        ];
+        let graph = graph((0..5).map(node).collect(), channels);
+        let sizing = SizingConfig {
+            capacity_scale: Some(4.0),
+            ..Default::default()
+        };
+
+        let factors = sizing.capacity_factors(&graph, &ckb, &[1, 4].map(peer).into());
+        let expected: HashMap<_, _> = [(peer(1), 1.0), (peer(4), 4.0)].into();
+        assert_eq!(factors, expected);
+    }
+}
@@ -0,0 +1,106 @@
+//! Funds reserved by agents sharing a wallet
+//!
+//! Agents query the balance of the same funding lock, funds of an open are reserved from
+//! selection until the open fails, expires or is settled so other agents don't count them again.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use ckb_jsonrpc_types::Script;
+use fnn::rpc::peer::PeerId;
+
+use crate::config::BudgetConfig;
+
+/// Funds of an open, `ckb` is the CKB a UDT open takes from the CKB pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    /// Type script of the token, `None` for CKB
+    pub pool: Option<Script>,
+    pub amount: u128,
+    pub ckb: u128,
+}
+
+impl Reservation {
+    /// Funds reserved from `pool`
+    fn of(&self, pool: Option<&Script>) -> u128 {
+        let mut amount = 0;
+        if self.pool.as_ref() == pool {
+            amount += self.amount;
+        }
+        if pool.is_none() {
+            amount += self.ckb;
+        }
+        amount
+    }
+}
+
+/// Process-wide reservations keyed by agent and peer, clones share the reservations
+#[derive(Debug, Clone, Default)]
+pub struct BudgetCoordinator {
+    reservations: Arc<Mutex<HashMap<String, HashMap<PeerId, Reservation>>>>,
+}
+
+impl BudgetCoordinator {
+    pub fn reserve(&self, agent: &str, peer: PeerId, reservation: Reservation) {
+        self.reservations
+            .lock()
+            .expect("lock")
+            .entry(agent.to_string())
+            .or_default()
+            .insert(peer, reservation);
+    }
+
+    pub fn release(&self, agent: &str, peer: &PeerId) -> Option<Reservation> {
+        let mut reservations = self.reservations.lock().expect("lock");
+        let reservation = reservations.get_mut(agent)?.remove(peer);
+        if reservations.get(agent).is_some_and(|r| r.is_empty()) {
+            reservations.remove(agent);
+        }
+        reservation
+    }
+
+    /// Funds of `pool` reserved by `agent`
+    pub fn reserved_by(&self, agent: &str, pool: Option<&Script>) -> u128 {
+        self.reservations
+            .lock()
+            .expect("lock")
+            .get(agent)
+            .map(|r| r.values().map(|r| r.of(pool)).sum())
+            .unwrap_or_default()
+    }
+
+    /// Funds of `pool` reserved by agents other than `agent`
+    pub fn reserved_by_others(&self, agent: &str, pool: Option<&Script>) -> u128 {
+        self.reservations
+            .lock()
+            .expect("lock")
+            .iter()
+            .filter(|(name, _)| name.as_str() != agent)
+            .flat_map(|(_, r)| r.values())
+            .map(|r| r.of(pool))
+            .sum()
+    }
+}
+
+impl BudgetConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(share) = self.share {
+            if !(0.0..=1.0).contains(&share) {
+                bail!("budget share must be within 0.0 ~ 1.0, got {share}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Max funds the agent may have reserved out of `balance`, `None` if unlimited
+    pub fn limit(&self, balance: u128) -> Option<u128> {
+        let share = self.share.map(|s| (balance as f64 * s) as u128);
+        match (share, self.cap) {
+            (Some(share), Some(cap)) => Some(share.min(cap)),
+            (share, cap) => share.or(cap),
+        }
+    }
+}
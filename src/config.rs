@@ -1,10 +1,18 @@
+use std::path::PathBuf;
+
 use ckb_jsonrpc_types::Script;
-use fnn::{fiber::serde_utils::U128Hex, rpc::peer::MultiAddr};
+use fnn::{
+    fiber::serde_utils::{U128Hex, U64Hex},
+    rpc::peer::MultiAddr,
+};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
+    /// Directory of the agent state store
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
     pub fiber: FiberConfig,
     pub ckb: CkbConfig,
     pub agents: Vec<AgentConfig>,
@@ -13,6 +21,27 @@ pub struct Config {
 #[derive(Serialize, Deserialize)]
 pub struct FiberConfig {
     pub url: String,
+    #[serde(default, flatten)]
+    pub graph_query: GraphQueryConfig,
+}
+
+/// Pagination of graph queries
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct GraphQueryConfig {
+    /// Nodes or channels fetched in a page
+    pub graph_page_size: u64,
+    /// Stop fetching after max pages to protect us from an endless cursor
+    pub graph_max_pages: usize,
+}
+
+impl Default for GraphQueryConfig {
+    fn default() -> Self {
+        Self {
+            graph_page_size: 500,
+            graph_max_pages: 1000,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,31 +49,153 @@ pub struct CkbConfig {
     pub url: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum Heuristic {
-    Random,
-    Centrality,
-    Richness,
+/// Cost of a channel edge when computing shortest paths
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathCost {
+    /// Every channel costs one hop
+    #[default]
+    Hop,
+    /// Proportional fee charged by the forwarding node
+    Fee,
+    /// Inverse of the channel capacity, large channels are cheap
+    Capacity,
+}
+
+/// Source nodes of betweenness centrality
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(tag = "type")]
+pub enum CentralitySampling {
+    /// Shortest paths from every node
+    #[default]
+    Exact,
+    /// Shortest paths from `k` random pivots, results are scaled to the whole graph
+    Pivots { k: usize },
+    /// Sample enough pivots so the normalized error is within `epsilon` with 90% confidence
+    ErrorBound { epsilon: f64 },
+}
+
+/// Centrality of the self node measured by `MarginalCentrality`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CentralityMetric {
+    /// Harmonic closeness, sum of inverse hop distances to other nodes
+    #[default]
+    Closeness,
+    /// Betweenness, sources are sampled by `sampling`, one pass per pivot and candidate
+    Betweenness,
+}
+
+/// Nodes favoured by `Stability`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AgePreference {
+    /// Long-lived nodes are less likely to strand our funds
+    #[default]
+    Established,
+    /// Young nodes, to help grow the network
+    Newcomer,
+}
+
+/// Handling of fee rates above `max_fee_rate` in `Fee`, fee rates are in millionths
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum FeeOutliers {
+    #[default]
+    Keep,
+    /// Count outliers as `max_fee_rate`
+    Cap {
+        #[serde_as(as = "U64Hex")]
+        max_fee_rate: u64,
+    },
+    /// Ignore outliers, a node charging only outliers scores zero
+    Exclude {
+        #[serde_as(as = "U64Hex")]
+        max_fee_rate: u64,
+    },
+}
+
+/// Normalization of the scores a heuristic gives to candidates
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(tag = "type")]
+pub enum Normalization {
+    /// Keep scores as the heuristic gives
+    #[default]
+    None,
+    /// Scale to 0.0 ~ 1.0 by the min and max score, equal scores are zero
+    MinMax,
+    /// Percentile of the rank, ties share the average rank
+    Rank,
+    /// Z-score clipped to `±clip` then scaled to 0.0 ~ 1.0
+    ZScore {
+        #[serde(default = "default_zscore_clip")]
+        clip: f64,
+    },
 }
 
+/// Transform of normalized scores, transforms of a heuristic apply in order
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum Transform {
+    /// `1.0 - score`, for heuristics where lower is better
+    Invert,
+    /// `score ^ exponent`, exponents above 1 favour top scores
+    Power { exponent: f64 },
+    /// Scores below `min` count zero
+    Threshold { min: f64 },
+}
+
+/// Combination of the scores of heuristics
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(tag = "type")]
+pub enum Combiner {
+    /// Sum of `weight * score`
+    #[default]
+    WeightedSum,
+    /// Product of `score ^ (weight / total weight)`, a zero score vetoes the candidate
+    WeightedProduct,
+    /// Reciprocal rank fusion, sum of `weight / (k + rank)` where rank starts from 1
+    ReciprocalRank {
+        #[serde(default = "default_rrf_k")]
+        k: f64,
+    },
+}
+
+/// Params of a heuristic, keys other than `heuristic` and `weight` in the config item
+pub type HeuristicParams = serde_json::Map<String, serde_json::Value>;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HeuristicItem {
-    pub heuristic: Heuristic,
+    /// Name of a registered heuristic
+    pub heuristic: String,
     pub weight: f32,
+    /// Normalization of scores before they are combined
+    #[serde(default)]
+    pub normalize: Normalization,
+    /// Transforms applied after normalization
+    #[serde(default)]
+    pub transforms: Vec<Transform>,
+    #[serde(flatten)]
+    pub params: HeuristicParams,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HeuristicConfig {
     pub heuristics: Vec<HeuristicItem>,
+    /// Combination of the scores of heuristics
+    #[serde(default)]
+    pub combine: Combiner,
 }
 
 impl Default for HeuristicConfig {
     fn default() -> Self {
         Self {
             heuristics: vec![HeuristicItem {
-                heuristic: Heuristic::Centrality,
+                heuristic: "Centrality".to_string(),
                 weight: 1.0,
+                normalize: Default::default(),
+                transforms: Default::default(),
+                params: Default::default(),
             }],
+            combine: Default::default(),
         }
     }
 }
@@ -64,6 +215,14 @@ impl TokenType {
         }
     }
 
+    /// Type script of the token, `None` for CKB
+    pub fn script(&self) -> Option<&Script> {
+        match self {
+            Self::Ckb => None,
+            Self::Udt { script, .. } => Some(script),
+        }
+    }
+
     pub fn is_token(&self, script: Option<Script>) -> bool {
         match (self, script) {
             (Self::Ckb, None) => true,
@@ -73,11 +232,41 @@ impl TokenType {
     }
 }
 
+/// Channels an agent scores candidates on
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GraphView {
+    /// Channels of the agent's token only
+    #[default]
+    Token,
+    /// Channels of all tokens
+    All,
+    /// Channels of the agent's token and CKB channels
+    TokenAndCkb,
+}
+
+impl GraphView {
+    /// Whether a channel funded by `script` is in the view of `token`
+    pub fn contains(&self, token: &TokenType, script: Option<Script>) -> bool {
+        match self {
+            Self::Token => token.is_token(script),
+            Self::All => true,
+            Self::TokenAndCkb => script.is_none() || token.is_token(script),
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AgentConfig {
+    /// Name the agent state is stored under, defaults to the token name. Required to tell
+    /// agents of the same token apart
+    #[serde(default)]
+    pub name: Option<String>,
     /// Set token type
     pub token: TokenType,
+    /// Channels of the graph candidates are scored on
+    #[serde(default)]
+    pub graph_view: GraphView,
     /// Open channals to external nodes without scoring
     pub external_nodes: Vec<MultiAddr>,
     /// Max channels
@@ -86,6 +275,16 @@ pub struct AgentConfig {
     pub interval: u64,
     /// Max pending channels
     pub max_pending: usize,
+    /// Seconds before a pending channel is expired, channels accepted by the peer are kept
+    /// until their funding is confirmed
+    #[serde(default = "default_pending_timeout")]
+    pub pending_timeout: u64,
+    /// Abandon expired pending channels on the node to release the funds
+    #[serde(default)]
+    pub abandon_expired: bool,
+    /// Seconds to wait before retrying a peer whose last open failed or expired
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: u64,
     /// Minimal chan size
     #[serde_as(as = "U128Hex")]
     pub min_chan_funds: u128,
@@ -94,4 +293,200 @@ pub struct AgentConfig {
     pub max_chan_funds: u128,
     #[serde(default, flatten)]
     pub heuristics: HeuristicConfig,
+    /// Selection of candidates by their scores
+    #[serde(default)]
+    pub selection: SelectionConfig,
+    /// Funds of each selected candidate
+    #[serde(default)]
+    pub allocation: Allocation,
+    /// Adjust allocated funds to each candidate
+    #[serde(default)]
+    pub sizing: SizingConfig,
+    /// Funds kept out of channels
+    #[serde(default)]
+    pub reserve: ReserveConfig,
+    /// Funds of the wallet the agent may take when other agents share it
+    #[serde(default)]
+    pub budget: BudgetConfig,
+    /// Close channels with dead or departed peers
+    #[serde(default)]
+    pub prune: PruneConfig,
+}
+
+impl AgentConfig {
+    /// Stable name of the agent, the key of its state in the store
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_else(|| self.token.name())
+    }
+}
+
+/// Pick candidates from scores, candidates scoring zero or less are never picked
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(tag = "type")]
+pub enum SelectionStrategy {
+    /// Highest scores first
+    TopK,
+    /// Sample without replacement weighted by score
+    #[default]
+    Weighted,
+    /// Sample without replacement weighted by `exp(score / temperature)`,
+    /// low temperatures approach `TopK`
+    Softmax {
+        #[serde(default = "default_temperature")]
+        temperature: f64,
+    },
+    /// Each pick is uniformly random with probability `epsilon`, otherwise the best left
+    EpsilonGreedy { epsilon: f64 },
+}
+
+/// Selection of candidates
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SelectionConfig {
+    #[serde(flatten)]
+    pub strategy: SelectionStrategy,
+    /// Seed of every round, a random seed is drawn and logged each round if not set
+    pub seed: Option<u64>,
+}
+
+/// Per-peer adjustment of allocated funds, results stay within `max_chan_funds`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SizingConfig {
+    /// Raise funds to the minimum the candidate auto accepts when it is within `max_chan_funds`
+    pub honor_peer_min: bool,
+    /// Scale funds by the median capacity of the candidate's channels over the median of all
+    /// channels of the agent's token, the factor is bounded within `1 / capacity_scale ~
+    /// capacity_scale`
+    pub capacity_scale: Option<f64>,
+}
+
+impl Default for SizingConfig {
+    fn default() -> Self {
+        Self {
+            honor_peer_min: true,
+            capacity_scale: None,
+        }
+    }
+}
+
+/// Funds kept in the wallet for on-chain fees, cell occupancy and closing channels
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ReserveConfig {
+    /// Amount never committed to channels
+    #[serde_as(as = "U128Hex")]
+    pub amount: u128,
+    /// Percent of the balance never committed to channels, the larger of `amount` and
+    /// the percent is reserved
+    pub percent: f64,
+    /// Estimated fee and cell occupancy of an open, in the agent's token.
+    /// Fees of UDT channels are paid in CKB so UDT agents usually leave it zero
+    #[serde_as(as = "U128Hex")]
+    pub open_fee: u128,
+}
+
+/// Budget of an agent in the wallet shared by agents, funds of an open are reserved until
+/// it fails, expires or is settled
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// Fraction of the spendable balance of the token the agent may reserve
+    pub share: Option<f64>,
+    /// Max funds the agent may reserve
+    #[serde_as(as = "Option<U128Hex>")]
+    pub cap: Option<u128>,
+    /// CKB a UDT open reserves from the CKB balance for cell capacity and fees
+    #[serde_as(as = "U128Hex")]
+    pub ckb_per_open: u128,
+}
+
+/// Funds of selected candidates, sizes are clamped to `min_chan_funds` ~ `max_chan_funds`
+/// and candidates that can't be funded are dropped
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type")]
+pub enum Allocation {
+    /// `max_chan_funds` for every candidate
+    #[default]
+    Fixed,
+    /// Share of the available funds proportional to the score among selected candidates
+    Proportional,
+    /// Funds of the highest tier the score percentile of the candidate reaches,
+    /// `min_chan_funds` below all tiers
+    Tiered { tiers: Vec<AllocationTier> },
+    /// Split `budget` of a round evenly, the lowest scored candidates are dropped until
+    /// each gets `min_chan_funds`
+    EvenSplit {
+        #[serde_as(as = "U128Hex")]
+        budget: u128,
+    },
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AllocationTier {
+    /// Percentile of the score among all scored candidates, from 0.0 to 1.0
+    pub percentile: f64,
+    #[serde_as(as = "U128Hex")]
+    pub funds: u128,
+}
+
+/// Pruning of local channels
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PruneConfig {
+    /// Pruning is disabled by default
+    pub enabled: bool,
+    /// Seconds a channel must stay prunable before it is closed
+    pub grace_period: u64,
+    /// A peer is dead if its node announcement is older than seconds
+    pub unreachable_after: u64,
+    /// Max channels closed in a round
+    pub max_close_per_round: usize,
+    /// Fee rate of the shutdown transaction, the node default is used if not set
+    #[serde_as(as = "Option<U64Hex>")]
+    pub fee_rate: Option<u64>,
+    /// Script receiving our funds, the node default is used if not set
+    pub close_script: Option<Script>,
+}
+
+impl Default for PruneConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            grace_period: 86400,
+            unreachable_after: 7 * 86400,
+            max_close_per_round: 1,
+            fee_rate: None,
+            close_script: None,
+        }
+    }
+}
+
+fn default_zscore_clip() -> f64 {
+    3.0
+}
+
+fn default_rrf_k() -> f64 {
+    60.0
+}
+
+fn default_temperature() -> f64 {
+    1.0
+}
+
+fn default_pending_timeout() -> u64 {
+    600
+}
+
+fn default_retry_backoff() -> u64 {
+    3600
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("data")
 }
@@ -1,5 +1,9 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+};
 
+use ckb_jsonrpc_types::Script;
 use fnn::{
     fiber::types::Pubkey,
     rpc::{
@@ -7,24 +11,109 @@ use fnn::{
         peer::PeerId,
     },
 };
-use tracing::{trace, warn};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    config::{GraphView, TokenType},
+    utils::conv,
+};
+
+/// A directed channel edge from a node to its adjacent node
+#[derive(Debug, Clone)]
+pub struct Edge {
+    /// Index of the adjacent node
+    pub to: usize,
+    /// Index of the channel in `channels`
+    pub channel: usize,
+    pub capacity: u128,
+    /// `None` for CKB channels
+    pub udt_type_script: Option<Script>,
+    /// Proportional fee in millionths charged by the node forwarding over the edge,
+    /// `None` if the node does not report it
+    pub fee_rate: Option<u64>,
+}
 
 pub struct Graph {
     nodes: Vec<NodeInfo>,
     channels: Vec<ChannelInfo>,
     edges: Vec<Vec<usize>>,
+    channel_edges: Vec<Vec<Edge>>,
+    node_to_idx: HashMap<Pubkey, usize>,
+    fingerprint: u64,
 }
 
 impl Graph {
     pub fn build(nodes: Vec<NodeInfo>, channels: Vec<ChannelInfo>) -> Self {
-        let edges = compute_edges(&nodes, &channels);
+        // node pubkey to index map
+        let node_to_idx: HashMap<Pubkey, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.node_id, index))
+            .collect();
+        let channel_edges = compute_edges(&nodes, &channels, &node_to_idx);
+        let edges = channel_edges
+            .iter()
+            .map(|edges| edges.iter().map(|e| e.to).collect())
+            .collect();
+        let fingerprint = compute_fingerprint(&nodes, &channel_edges);
         Self {
             nodes,
             channels,
             edges,
+            channel_edges,
+            node_to_idx,
+            fingerprint,
         }
     }
 
+    /// Build the graph with channels in the `view` of `token`
+    pub fn build_view(
+        nodes: Vec<NodeInfo>,
+        channels: Vec<ChannelInfo>,
+        token: &TokenType,
+        view: GraphView,
+    ) -> Self {
+        let total = channels.len();
+        let channels: Vec<ChannelInfo> = channels
+            .into_iter()
+            .filter(|c| view.contains(token, c.udt_type_script.as_ref().map(|s| conv!(s))))
+            .collect();
+        debug!(
+            "Graph view {view:?} of token {} has {} of {total} channels",
+            token.name(),
+            channels.len()
+        );
+        Self::build(nodes, channels)
+    }
+
+    /// Hash of nodes and channel edges, graphs with the same fingerprint give the same paths
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Index of a node in `nodes`
+    pub fn node_index(&self, node_id: &Pubkey) -> Option<usize> {
+        self.node_to_idx.get(node_id).cloned()
+    }
+
+    /// Hop distances from node `s` by BFS, `None` if unreachable
+    pub fn hop_distances(&self, s: usize) -> Vec<Option<usize>> {
+        let mut dist = vec![None; self.nodes.len()];
+        let mut queue = VecDeque::default();
+        dist[s] = Some(0);
+        queue.push_back(s);
+        while let Some(v) = queue.pop_front() {
+            let d = dist[v].unwrap_or_default();
+            for &w in &self.edges[v] {
+                if dist[w].is_none() {
+                    dist[w] = Some(d + 1);
+                    queue.push_back(w);
+                }
+            }
+        }
+        dist
+    }
+
     pub fn nodes(&self) -> &[NodeInfo] {
         &self.nodes
     }
@@ -33,19 +122,23 @@ impl Graph {
         &self.channels
     }
 
+    /// Adjacent nodes of each node, a node appears once per channel
     pub fn edges(&self) -> &[Vec<usize>] {
         &self.edges
     }
+
+    /// Channel edges of each node with capacity, token and fee policy
+    pub fn channel_edges(&self) -> &[Vec<Edge>] {
+        &self.channel_edges
+    }
 }
 
-/// Compuate adjacent nodes
-fn compute_edges(nodes: &[NodeInfo], channels: &[ChannelInfo]) -> Vec<Vec<usize>> {
-    // node pubkey to index map
-    let node_to_idx: HashMap<Pubkey, usize> = nodes
-        .iter()
-        .enumerate()
-        .map(|(index, node)| (node.node_id, index))
-        .collect();
+/// Compuate adjacent channel edges
+fn compute_edges(
+    nodes: &[NodeInfo],
+    channels: &[ChannelInfo],
+    node_to_idx: &HashMap<Pubkey, usize>,
+) -> Vec<Vec<Edge>> {
     // node index to channel ids map
     let mut node_channels: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
 
@@ -86,11 +179,80 @@ fn compute_edges(nodes: &[NodeInfo], channels: &[ChannelInfo]) -> Vec<Vec<usize>
 
             let n1 = node_to_idx[&c.node1];
             let n2 = node_to_idx[&c.node2];
-            // push adjacent node v
-            let v_idx = if n_idx == n1 { n2 } else { n1 };
-            edges[n_idx].push(v_idx);
+            // push adjacent node v, the forwarding node charges the fee
+            let (v_idx, fee_rate) = if n_idx == n1 {
+                (n2, c.fee_rate_of_node1)
+            } else {
+                (n1, c.fee_rate_of_node2)
+            };
+            edges[n_idx].push(Edge {
+                to: v_idx,
+                channel: *c_idx,
+                capacity: c.capacity,
+                udt_type_script: c.udt_type_script.as_ref().map(|s| conv!(s)),
+                fee_rate,
+            });
         }
     }
 
     edges
 }
+
+fn compute_fingerprint(nodes: &[NodeInfo], channel_edges: &[Vec<Edge>]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (node, edges) in nodes.iter().zip(channel_edges) {
+        node.node_id.hash(&mut hasher);
+        for e in edges {
+            e.to.hash(&mut hasher);
+            e.capacity.hash(&mut hasher);
+            e.fee_rate.hash(&mut hasher);
+            format!("{:?}", e.udt_type_script).hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::Graph;
+    use crate::{
+        config::{GraphView, TokenType},
+        testing::{channel, node, token_channel, udt},
+    };
+
+    #[test]
+    fn test_build_view() {
+        // a line 0 - 1 - 2 - 3 of a CKB channel and channels of two UDTs
+        let (a, b) = (udt(1), udt(2));
+        let channels = vec![
+            channel(0, 0, 1),
+            token_channel(1, 1, 2, &a, 2000),
+            token_channel(2, 2, 3, &b, 3000),
+        ];
+        let build = |token: &TokenType, view: GraphView| {
+            let nodes: Vec<Value> = (0..4).map(node).collect();
+            Graph::build_view(
+                serde_json::from_value(json!(nodes)).expect("nodes"),
+                serde_json::from_value(json!(channels)).expect("channels"),
+                token,
+                view,
+            )
+        };
+        let capacities =
+            |graph: &Graph| -> Vec<u128> { graph.channels().iter().map(|c| c.capacity).collect() };
+
+        let graph = build(&TokenType::Ckb, GraphView::Token);
+        assert_eq!(capacities(&graph), vec![1000]);
+        assert_eq!(graph.hop_distances(0), vec![Some(0), Some(1), None, None]);
+        assert_eq!(capacities(&build(&a, GraphView::Token)), vec![2000]);
+        assert_eq!(
+            capacities(&build(&a, GraphView::TokenAndCkb)),
+            vec![1000, 2000]
+        );
+        let graph = build(&a, GraphView::All);
+        assert_eq!(capacities(&graph), vec![1000, 2000, 3000]);
+        assert_eq!(graph.hop_distances(0)[3], Some(3));
+    }
+}
@@ -0,0 +1,265 @@
+//! Programmable in-memory graph source for tests
+//!
+//! Responses are kept as JSON and decoded on each call. A method returns its scripted
+//! responses in order, then falls back to the default response. Every call is recorded
+//! so tests can assert what the agent requested.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use ckb_jsonrpc_types::Script;
+use fnn::{
+    fiber::types::Hash256,
+    rpc::{
+        channel::{Channel, OpenChannelParams},
+        graph::{ChannelInfo, NodeInfo},
+        info::NodeInfoResult,
+        peer::{MultiAddr, PeerId},
+    },
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::{
+    config::TokenType,
+    traits::{Balance, GraphSource},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    NodeInfo,
+    LocalChannels,
+    GraphNodes,
+    GraphChannels,
+    ConnectPeer,
+    OpenChannel,
+    AbandonChannel,
+    ShutdownChannel,
+    GetBalance,
+}
+
+/// A recorded call
+#[derive(Debug, Clone)]
+pub enum Call {
+    NodeInfo,
+    LocalChannels,
+    GraphNodes,
+    GraphChannels,
+    ConnectPeer { address: MultiAddr },
+    OpenChannel { peer: PeerId, funds: u128 },
+    AbandonChannel { channel_id: Hash256 },
+    ShutdownChannel { channel_id: Hash256 },
+    GetBalance { token: String },
+}
+
+impl Call {
+    pub fn method(&self) -> Method {
+        match self {
+            Self::NodeInfo => Method::NodeInfo,
+            Self::LocalChannels => Method::LocalChannels,
+            Self::GraphNodes => Method::GraphNodes,
+            Self::GraphChannels => Method::GraphChannels,
+            Self::ConnectPeer { .. } => Method::ConnectPeer,
+            Self::OpenChannel { .. } => Method::OpenChannel,
+            Self::AbandonChannel { .. } => Method::AbandonChannel,
+            Self::ShutdownChannel { .. } => Method::ShutdownChannel,
+            Self::GetBalance { .. } => Method::GetBalance,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    defaults: HashMap<Method, Value>,
+    scripted: HashMap<Method, VecDeque<Result<Value, String>>>,
+    calls: Vec<Call>,
+    opened: usize,
+}
+
+#[derive(Clone)]
+pub struct MemoryGraphSource {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Debug for MemoryGraphSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryGraphSource").finish()
+    }
+}
+
+impl Default for MemoryGraphSource {
+    fn default() -> Self {
+        let source = Self {
+            inner: Default::default(),
+        };
+        source.set(Method::LocalChannels, json!([]));
+        source.set(Method::GraphNodes, json!([]));
+        source.set(Method::GraphChannels, json!([]));
+        source.set(Method::ConnectPeer, Value::Null);
+        source.set(Method::AbandonChannel, Value::Null);
+        source.set(Method::ShutdownChannel, Value::Null);
+        source.set_balance(0);
+        source
+    }
+}
+
+impl MemoryGraphSource {
+    /// Set the default response of a method
+    pub fn set(&self, method: Method, response: Value) {
+        let mut inner = self.inner.lock().expect("lock");
+        inner.defaults.insert(method, response);
+    }
+
+    /// Script the response of the next unscripted call of a method
+    pub fn push(&self, method: Method, response: Result<Value, String>) {
+        let mut inner = self.inner.lock().expect("lock");
+        inner
+            .scripted
+            .entry(method)
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Fail the next unscripted call of a method
+    pub fn fail_next(&self, method: Method, reason: &str) {
+        self.push(method, Err(reason.to_string()));
+    }
+
+    pub fn set_graph(&self, nodes: Vec<Value>, channels: Vec<Value>) {
+        self.set(Method::GraphNodes, Value::Array(nodes));
+        self.set(Method::GraphChannels, Value::Array(channels));
+    }
+
+    pub fn set_local_channels(&self, channels: Vec<Value>) {
+        self.set(Method::LocalChannels, Value::Array(channels));
+    }
+
+    pub fn set_balance(&self, total: u128) {
+        let balance = Balance {
+            total,
+            ..Default::default()
+        };
+        self.set(
+            Method::GetBalance,
+            serde_json::to_value(balance).expect("balance"),
+        );
+    }
+
+    /// Take recorded calls, the record is cleared
+    pub fn take_calls(&self) -> Vec<Call> {
+        let mut inner = self.inner.lock().expect("lock");
+        std::mem::take(&mut inner.calls)
+    }
+
+    /// Assert calls of a method since the last `take_calls`
+    pub fn assert_calls(&self, method: Method, expected: usize) {
+        let inner = self.inner.lock().expect("lock");
+        let n = inner.calls.iter().filter(|c| c.method() == method).count();
+        assert_eq!(n, expected, "calls of {method:?}: {:?}", inner.calls);
+    }
+
+    /// Assert opened peers and funds since the last `take_calls`, in any order
+    pub fn assert_opened(&self, expected: &[(PeerId, u128)]) {
+        let inner = self.inner.lock().expect("lock");
+        let mut opened: Vec<String> = inner
+            .calls
+            .iter()
+            .filter_map(|c| match c {
+                Call::OpenChannel { peer, funds } => Some(format!("{peer} {funds}")),
+                _ => None,
+            })
+            .collect();
+        let mut expected: Vec<String> = expected
+            .iter()
+            .map(|(peer, funds)| format!("{peer} {funds}"))
+            .collect();
+        opened.sort();
+        expected.sort();
+        assert_eq!(opened, expected, "opened channels");
+    }
+
+    fn respond<T: DeserializeOwned>(&self, call: Call) -> Result<T> {
+        let mut inner = self.inner.lock().expect("lock");
+        let method = call.method();
+        inner.calls.push(call);
+        let response = match inner.scripted.get_mut(&method).and_then(|q| q.pop_front()) {
+            Some(r) => r.map_err(|reason| anyhow!(reason))?,
+            None if method == Method::OpenChannel => {
+                // temporary channel id is the sequence of opened channels
+                inner.opened += 1;
+                Value::String(format!("0x{:064x}", inner.opened))
+            }
+            None => inner
+                .defaults
+                .get(&method)
+                .cloned()
+                .ok_or_else(|| anyhow!("no response of {method:?}"))?,
+        };
+        Ok(serde_json::from_value(response)?)
+    }
+}
+
+#[allow(clippy::manual_async_fn)]
+impl GraphSource for MemoryGraphSource {
+    fn node_info(&self) -> impl Future<Output = Result<NodeInfoResult>> + Send {
+        async { self.respond(Call::NodeInfo) }
+    }
+
+    fn local_channels(&self) -> impl Future<Output = Result<Vec<Channel>>> + Send {
+        async { self.respond(Call::LocalChannels) }
+    }
+
+    fn graph_nodes(&self) -> impl Future<Output = Result<Vec<NodeInfo>>> + Send {
+        async { self.respond(Call::GraphNodes) }
+    }
+
+    fn graph_channels(&self) -> impl Future<Output = Result<Vec<ChannelInfo>>> + Send {
+        async { self.respond(Call::GraphChannels) }
+    }
+
+    fn connect_peer(&self, addr: MultiAddr) -> impl Future<Output = Result<()>> + Send {
+        async move { self.respond(Call::ConnectPeer { address: addr }) }
+    }
+
+    fn open_channel(
+        &self,
+        params: OpenChannelParams,
+    ) -> impl Future<Output = Result<Hash256>> + Send {
+        async move {
+            self.respond(Call::OpenChannel {
+                peer: params.peer_id,
+                funds: params.funding_amount,
+            })
+        }
+    }
+
+    fn abandon_channel(&self, channel_id: Hash256) -> impl Future<Output = Result<()>> + Send {
+        async move { self.respond(Call::AbandonChannel { channel_id }) }
+    }
+
+    fn shutdown_channel(
+        &self,
+        channel_id: Hash256,
+        _close_script: Option<Script>,
+        _fee_rate: Option<u64>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move { self.respond(Call::ShutdownChannel { channel_id }) }
+    }
+
+    fn get_balance(
+        &self,
+        _lock: Script,
+        token: TokenType,
+    ) -> impl Future<Output = Result<Balance>> + Send {
+        async move {
+            self.respond(Call::GetBalance {
+                token: token.name().to_string(),
+            })
+        }
+    }
+}
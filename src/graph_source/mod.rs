@@ -1 +1,4 @@
+#[cfg(test)]
+pub mod memory;
 pub mod rpc;
+pub mod snapshot;
@@ -1,6 +1,6 @@
 use std::{fmt::Debug, future::Future};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ckb_jsonrpc_types::Script;
 use ckb_sdk::{
     rpc::ckb_indexer::{Order, ScriptType, SearchKey, SearchKeyFilter, SearchMode},
@@ -9,19 +9,33 @@ use ckb_sdk::{
 use fnn::{
     fiber::types::Hash256,
     rpc::{
-        channel::{Channel, ListChannelsParams, OpenChannelParams},
+        channel::{
+            AbandonChannelParams, Channel, ListChannelsParams, OpenChannelParams,
+            ShutdownChannelParams,
+        },
         graph::{ChannelInfo, GraphChannelsParams, GraphNodesParams, NodeInfo},
         info::NodeInfoResult,
         peer::{ConnectPeerParams, MultiAddr},
     },
 };
 
-use crate::{config::TokenType, rpc::client::RPCClient, traits::GraphSource};
+use tracing::{info, warn};
+
+use crate::{
+    config::{GraphQueryConfig, TokenType},
+    rpc::client::RPCClient,
+    traits::{Balance, GraphSource},
+    utils::conv,
+};
+
+/// Cells fetched in a page when summing UDT balance
+const UDT_CELLS_PAGE_SIZE: u32 = 1000;
 
 #[derive(Clone)]
 pub struct RPCGraphSource {
     fiber_client: RPCClient,
     ckb_client: CkbRpcAsyncClient,
+    graph_query: GraphQueryConfig,
 }
 
 impl Debug for RPCGraphSource {
@@ -31,11 +45,25 @@ impl Debug for RPCGraphSource {
 }
 
 impl RPCGraphSource {
-    pub fn new(fiber_client: RPCClient, ckb_client: CkbRpcAsyncClient) -> Self {
+    pub fn new(
+        fiber_client: RPCClient,
+        ckb_client: CkbRpcAsyncClient,
+        graph_query: GraphQueryConfig,
+    ) -> Self {
         Self {
             fiber_client,
             ckb_client,
+            graph_query,
+        }
+    }
+}
+
+impl GraphQueryConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.graph_page_size == 0 {
+            bail!("graph_page_size must be positive");
         }
+        Ok(())
     }
 }
 
@@ -46,30 +74,70 @@ impl GraphSource for RPCGraphSource {
     }
 
     fn graph_nodes(&self) -> impl Future<Output = Result<Vec<NodeInfo>>> {
-        // TODO: fetch all nodes
         async {
-            self.fiber_client
-                .graph_nodes(GraphNodesParams {
-                    limit: None,
-                    after: None,
-                })
-                .await
-                .map(|r| r.nodes)
-                .map_err(Into::into)
+            let GraphQueryConfig {
+                graph_page_size,
+                graph_max_pages,
+            } = self.graph_query;
+            let mut nodes = Vec::new();
+            let mut after = None;
+            let mut pages = 0;
+            loop {
+                let r = self
+                    .fiber_client
+                    .graph_nodes(GraphNodesParams {
+                        limit: Some(graph_page_size),
+                        after,
+                    })
+                    .await?;
+                let len = r.nodes.len();
+                nodes.extend(r.nodes);
+                pages += 1;
+                if (len as u64) < graph_page_size || r.last_cursor.is_empty() {
+                    break;
+                }
+                if pages >= graph_max_pages {
+                    warn!("Stop fetching graph nodes after {pages} pages, the graph may be incomplete");
+                    break;
+                }
+                after = Some(r.last_cursor);
+            }
+            info!("Fetch {} graph nodes in {pages} pages", nodes.len());
+            Ok(nodes)
         }
     }
 
     fn graph_channels(&self) -> impl Future<Output = Result<Vec<ChannelInfo>>> {
-        // TODO: fetch all channels
         async {
-            self.fiber_client
-                .graph_channels(GraphChannelsParams {
-                    limit: None,
-                    after: None,
-                })
-                .await
-                .map(|r| r.channels)
-                .map_err(Into::into)
+            let GraphQueryConfig {
+                graph_page_size,
+                graph_max_pages,
+            } = self.graph_query;
+            let mut channels = Vec::new();
+            let mut after = None;
+            let mut pages = 0;
+            loop {
+                let r = self
+                    .fiber_client
+                    .graph_channels(GraphChannelsParams {
+                        limit: Some(graph_page_size),
+                        after,
+                    })
+                    .await?;
+                let len = r.channels.len();
+                channels.extend(r.channels);
+                pages += 1;
+                if (len as u64) < graph_page_size || r.last_cursor.is_empty() {
+                    break;
+                }
+                if pages >= graph_max_pages {
+                    warn!("Stop fetching graph channels after {pages} pages, the graph may be incomplete");
+                    break;
+                }
+                after = Some(r.last_cursor);
+            }
+            info!("Fetch {} graph channels in {pages} pages", channels.len());
+            Ok(channels)
         }
     }
 
@@ -108,11 +176,40 @@ impl GraphSource for RPCGraphSource {
         }
     }
 
+    fn abandon_channel(&self, channel_id: Hash256) -> impl Future<Output = Result<()>> {
+        async move {
+            self.fiber_client
+                .abandon_channel(AbandonChannelParams { channel_id })
+                .await
+                .map_err(Into::into)
+        }
+    }
+
+    fn shutdown_channel(
+        &self,
+        channel_id: Hash256,
+        close_script: Option<Script>,
+        fee_rate: Option<u64>,
+    ) -> impl Future<Output = Result<()>> {
+        async move {
+            let params = ShutdownChannelParams {
+                channel_id,
+                close_script: close_script.map(|s| conv!(s)),
+                fee_rate,
+                force: None,
+            };
+            self.fiber_client
+                .shutdown_channel(params)
+                .await
+                .map_err(Into::into)
+        }
+    }
+
     fn get_balance(
         &self,
         lock: Script,
         token: TokenType,
-    ) -> impl Future<Output = Result<u128>> + Send {
+    ) -> impl Future<Output = Result<Balance>> + Send {
         async {
             match token {
                 TokenType::Ckb => {
@@ -135,7 +232,11 @@ impl GraphSource for RPCGraphSource {
                     let source = self.clone();
                     let r = source.ckb_client.get_cells_capacity(search_key).await?;
                     let capacity = r.map(|cell| cell.capacity.value()).unwrap_or_default();
-                    Ok(capacity.into())
+                    Ok(Balance {
+                        total: capacity.into(),
+                        cell_count: None,
+                        largest_cell: None,
+                    })
                 }
                 TokenType::Udt { name: _, script } => {
                     let search_key = SearchKey {
@@ -154,29 +255,72 @@ impl GraphSource for RPCGraphSource {
                         with_data: Some(true),
                         group_by_transaction: None,
                     };
+                    let limit = UDT_CELLS_PAGE_SIZE;
+                    let mut balance = Balance {
+                        total: 0,
+                        cell_count: Some(0),
+                        largest_cell: Some(0),
+                    };
                     let source = self.clone();
-                    // TODO: handle paginate
-                    let r = source
-                        .ckb_client
-                        .get_cells(search_key, Order::Desc, 1000u32.into(), None)
-                        .await?;
-                    let capacity = r
-                        .objects
-                        .iter()
-                        .filter_map(|cell| {
-                            let data = cell.output_data.as_ref()?.as_bytes();
-                            if data.len() > 16 {
-                                let buf: [u8; 16] = data[..16].try_into().ok()?;
-                                let amount = u128::from_le_bytes(buf);
-                                Some(amount)
-                            } else {
-                                None
-                            }
-                        })
-                        .sum::<u128>();
-                    Ok(capacity)
+                    let mut after = None;
+                    loop {
+                        let r = source
+                            .ckb_client
+                            .get_cells(search_key.clone(), Order::Desc, limit.into(), after)
+                            .await?;
+                        for cell in &r.objects {
+                            let Some(amount) = cell
+                                .output_data
+                                .as_ref()
+                                .and_then(|data| parse_udt_amount(data.as_bytes()))
+                            else {
+                                continue;
+                            };
+                            balance.add_cell(amount);
+                        }
+                        if r.objects.len() < limit as usize || r.last_cursor.is_empty() {
+                            break;
+                        }
+                        after = Some(r.last_cursor);
+                    }
+                    Ok(balance)
                 }
             }
         }
     }
 }
+
+/// UDT amount is stored as u128 little endian in the first 16 bytes of cell data
+fn parse_udt_amount(data: &[u8]) -> Option<u128> {
+    let buf: [u8; 16] = data.get(..16)?.try_into().ok()?;
+    Some(u128::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_udt_amount;
+    use crate::config::GraphQueryConfig;
+
+    #[test]
+    fn test_parse_udt_amount() {
+        let amount: u128 = 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10;
+        let bytes = amount.to_le_bytes();
+
+        assert_eq!(parse_udt_amount(&bytes[..15]), None);
+        assert_eq!(parse_udt_amount(&bytes), Some(amount));
+        // data after the amount is ignored
+        let mut data = bytes.to_vec();
+        data.extend_from_slice(&[0xff; 16]);
+        assert_eq!(parse_udt_amount(&data), Some(amount));
+    }
+
+    #[test]
+    fn test_graph_query_config() {
+        assert!(GraphQueryConfig::default().validate().is_ok());
+        let config = GraphQueryConfig {
+            graph_page_size: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
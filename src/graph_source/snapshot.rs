@@ -0,0 +1,337 @@
+//! Graph source backed by a snapshot directory
+//!
+//! Queries are served from JSON files dumped by the `snapshot` command,
+//! connect, open and close requests are recorded instead of executed.
+
+use std::{
+    fmt::{Debug, Display},
+    fs,
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use ckb_jsonrpc_types::Script;
+use fnn::{
+    fiber::types::Hash256,
+    rpc::{
+        channel::{Channel, OpenChannelParams},
+        graph::{ChannelInfo, NodeInfo},
+        info::NodeInfoResult,
+        peer::{MultiAddr, PeerId},
+    },
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{
+    config::TokenType,
+    traits::{Balance, GraphSource},
+    utils::conv,
+};
+
+const NODE_INFO_FILE: &str = "node_info.json";
+const GRAPH_NODES_FILE: &str = "graph_nodes.json";
+const GRAPH_CHANNELS_FILE: &str = "graph_channels.json";
+const LOCAL_CHANNELS_FILE: &str = "local_channels.json";
+const BALANCES_FILE: &str = "balances.json";
+
+/// Balance of a token in the snapshot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenBalance {
+    pub token: TokenType,
+    pub balance: Balance,
+}
+
+/// Request recorded by the snapshot source
+#[derive(Debug, Clone)]
+pub enum RecordedAction {
+    ConnectPeer {
+        address: MultiAddr,
+    },
+    OpenChannel {
+        peer: PeerId,
+        funds: u128,
+        temporary_channel_id: Hash256,
+    },
+    AbandonChannel {
+        channel_id: Hash256,
+    },
+    ShutdownChannel {
+        channel_id: Hash256,
+        fee_rate: Option<u64>,
+    },
+}
+
+impl Display for RecordedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConnectPeer { address } => write!(f, "connect peer {address}"),
+            Self::OpenChannel {
+                peer,
+                funds,
+                temporary_channel_id,
+            } => write!(
+                f,
+                "open channel {temporary_channel_id:?} with {peer:?} funds {funds}"
+            ),
+            Self::AbandonChannel { channel_id } => write!(f, "abandon channel {channel_id:?}"),
+            Self::ShutdownChannel {
+                channel_id,
+                fee_rate,
+            } => write!(f, "shutdown channel {channel_id:?} fee rate {fee_rate:?}"),
+        }
+    }
+}
+
+/// Fiber types are kept as JSON and decoded on each query
+struct SnapshotData {
+    node_info: Value,
+    graph_nodes: Value,
+    graph_channels: Value,
+    local_channels: Value,
+    balances: Vec<TokenBalance>,
+}
+
+#[derive(Clone)]
+pub struct SnapshotGraphSource {
+    data: Arc<SnapshotData>,
+    actions: Arc<Mutex<Vec<RecordedAction>>>,
+}
+
+impl Debug for SnapshotGraphSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SnapshotGraphSource").finish()
+    }
+}
+
+impl SnapshotGraphSource {
+    /// Load a snapshot directory
+    pub fn load(dir: &Path) -> Result<Self> {
+        let read = |name: &str| -> Result<Value> {
+            let path = dir.join(name);
+            let raw = fs::read_to_string(&path)
+                .with_context(|| format!("read snapshot {}", path.display()))?;
+            serde_json::from_str(&raw).with_context(|| format!("parse snapshot {}", path.display()))
+        };
+        let data = SnapshotData {
+            node_info: read(NODE_INFO_FILE)?,
+            graph_nodes: read(GRAPH_NODES_FILE)?,
+            graph_channels: read(GRAPH_CHANNELS_FILE)?,
+            local_channels: read(LOCAL_CHANNELS_FILE)?,
+            balances: serde_json::from_value(read(BALANCES_FILE)?)?,
+        };
+        Ok(Self {
+            data: Arc::new(data),
+            actions: Default::default(),
+        })
+    }
+
+    /// Recorded requests in order
+    pub fn actions(&self) -> Vec<RecordedAction> {
+        self.actions.lock().expect("lock").clone()
+    }
+
+    fn record(&self, action: RecordedAction) {
+        info!("Record {action}");
+        self.actions.lock().expect("lock").push(action);
+    }
+}
+
+/// Dump data of a source into a snapshot directory, the CKB balance is always recorded since
+/// UDT agents query it for the CKB each open takes
+pub async fn dump<GS: GraphSource>(source: &GS, tokens: &[TokenType], dir: &Path) -> Result<()> {
+    let node_info = source.node_info().await?;
+    let lock: Script = conv!(&node_info.default_funding_lock_script);
+    let mut balances: Vec<TokenBalance> = Vec::default();
+    for token in std::iter::once(&TokenType::Ckb).chain(tokens) {
+        // agents may share a token
+        if balances
+            .iter()
+            .any(|b| b.token.is_token(token.script().cloned()))
+        {
+            continue;
+        }
+        let balance = source.get_balance(lock.clone(), token.clone()).await?;
+        balances.push(TokenBalance {
+            token: token.clone(),
+            balance,
+        });
+    }
+    let graph_nodes = source.graph_nodes().await?;
+    let graph_channels = source.graph_channels().await?;
+    let local_channels = source.local_channels().await?;
+
+    fs::create_dir_all(dir).with_context(|| format!("create snapshot {}", dir.display()))?;
+    let write = |name: &str, value: Value| -> Result<()> {
+        let path = dir.join(name);
+        let raw = serde_json::to_string_pretty(&value)?;
+        fs::write(&path, raw).with_context(|| format!("write snapshot {}", path.display()))
+    };
+    write(NODE_INFO_FILE, serde_json::to_value(&node_info)?)?;
+    write(GRAPH_NODES_FILE, serde_json::to_value(&graph_nodes)?)?;
+    write(GRAPH_CHANNELS_FILE, serde_json::to_value(&graph_channels)?)?;
+    write(LOCAL_CHANNELS_FILE, serde_json::to_value(&local_channels)?)?;
+    write(BALANCES_FILE, serde_json::to_value(&balances)?)?;
+
+    info!(
+        "Dump snapshot {} nodes {} channels {} locals {} balances {}",
+        dir.display(),
+        graph_nodes.len(),
+        graph_channels.len(),
+        local_channels.len(),
+        balances.len()
+    );
+    Ok(())
+}
+
+fn decode<T: DeserializeOwned>(value: &Value) -> Result<T> {
+    serde_json::from_value(value.clone()).map_err(Into::into)
+}
+
+#[allow(clippy::manual_async_fn)]
+impl GraphSource for SnapshotGraphSource {
+    fn node_info(&self) -> impl Future<Output = Result<NodeInfoResult>> + Send {
+        async { decode(&self.data.node_info) }
+    }
+
+    fn local_channels(&self) -> impl Future<Output = Result<Vec<Channel>>> + Send {
+        async { decode(&self.data.local_channels) }
+    }
+
+    fn graph_nodes(&self) -> impl Future<Output = Result<Vec<NodeInfo>>> + Send {
+        async { decode(&self.data.graph_nodes) }
+    }
+
+    fn graph_channels(&self) -> impl Future<Output = Result<Vec<ChannelInfo>>> + Send {
+        async { decode(&self.data.graph_channels) }
+    }
+
+    fn connect_peer(&self, addr: MultiAddr) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            self.record(RecordedAction::ConnectPeer { address: addr });
+            Ok(())
+        }
+    }
+
+    fn open_channel(
+        &self,
+        params: OpenChannelParams,
+    ) -> impl Future<Output = Result<Hash256>> + Send {
+        async move {
+            // temporary channel id is the sequence of the action
+            let seq = self.actions.lock().expect("lock").len() + 1;
+            let temporary_channel_id: Hash256 = decode(&Value::String(format!("0x{seq:064x}")))?;
+            self.record(RecordedAction::OpenChannel {
+                peer: params.peer_id,
+                funds: params.funding_amount,
+                temporary_channel_id,
+            });
+            Ok(temporary_channel_id)
+        }
+    }
+
+    fn abandon_channel(&self, channel_id: Hash256) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            self.record(RecordedAction::AbandonChannel { channel_id });
+            Ok(())
+        }
+    }
+
+    fn shutdown_channel(
+        &self,
+        channel_id: Hash256,
+        _close_script: Option<Script>,
+        fee_rate: Option<u64>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            self.record(RecordedAction::ShutdownChannel {
+                channel_id,
+                fee_rate,
+            });
+            Ok(())
+        }
+    }
+
+    fn get_balance(
+        &self,
+        _lock: Script,
+        token: TokenType,
+    ) -> impl Future<Output = Result<Balance>> + Send {
+        async move {
+            self.data
+                .balances
+                .iter()
+                .find(|b| b.token.is_token(token.script().cloned()))
+                .map(|b| b.balance)
+                .ok_or_else(|| anyhow!("No balance of token {} in the snapshot", token.name()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{dump, SnapshotGraphSource};
+    use crate::{
+        config::TokenType,
+        graph_source::memory::{MemoryGraphSource, Method},
+        testing::{channel, funding_lock, node, node_info},
+        traits::GraphSource,
+    };
+
+    async fn queries<GS: GraphSource>(source: &GS) -> Vec<Value> {
+        vec![
+            serde_json::to_value(source.node_info().await.expect("node info")).expect("json"),
+            serde_json::to_value(source.graph_nodes().await.expect("nodes")).expect("json"),
+            serde_json::to_value(source.graph_channels().await.expect("channels")).expect("json"),
+            serde_json::to_value(source.local_channels().await.expect("locals")).expect("json"),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_dump_and_load() {
+        let source = MemoryGraphSource::default();
+        source.set(Method::NodeInfo, node_info());
+        source.set_graph(
+            (0..3).map(node).collect(),
+            vec![channel(0, 0, 1), channel(1, 1, 2)],
+        );
+        source.set_balance(5000);
+        let dir = std::env::temp_dir().join(format!("snapshot-{}", std::process::id()));
+
+        dump(&source, &[TokenType::Ckb], &dir).await.expect("dump");
+        let snapshot = SnapshotGraphSource::load(&dir).expect("load");
+        let balance = snapshot.get_balance(funding_lock(), TokenType::Ckb).await;
+        let (expected, replayed) = (queries(&source).await, queries(&snapshot).await);
+        std::fs::remove_dir_all(&dir).expect("remove snapshot");
+
+        assert_eq!(replayed, expected);
+        assert_eq!(balance.expect("balance").total, 5000);
+    }
+
+    #[tokio::test]
+    async fn test_dump_ckb_balance() {
+        let source = MemoryGraphSource::default();
+        source.set(Method::NodeInfo, node_info());
+        source.set_balance(5000);
+        let dir = std::env::temp_dir().join(format!("snapshot-udt-{}", std::process::id()));
+        let udt = TokenType::Udt {
+            name: "udt".to_string(),
+            script: funding_lock(),
+        };
+
+        // a UDT agent still queries the CKB balance
+        dump(&source, &[udt.clone()], &dir).await.expect("dump");
+        let snapshot = SnapshotGraphSource::load(&dir).expect("load");
+        let ckb = snapshot.get_balance(funding_lock(), TokenType::Ckb).await;
+        let udt = snapshot.get_balance(funding_lock(), udt).await;
+        std::fs::remove_dir_all(&dir).expect("remove snapshot");
+
+        assert_eq!(ckb.expect("ckb balance").total, 5000);
+        assert_eq!(udt.expect("udt balance").total, 5000);
+    }
+}
@@ -3,20 +3,83 @@
 //! Some algorithm is learned from lnd project.
 
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    sync::Arc,
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
 };
 
-use fnn::rpc::{graph::NodeInfo, peer::PeerId};
+use fnn::rpc::peer::PeerId;
+use rand::{rngs::StdRng, seq::index::sample, SeedableRng};
+use serde::Deserialize;
+use tracing::debug;
 
-use crate::graph::Graph;
+use crate::{
+    config::{CentralitySampling, PathCost},
+    graph::{Edge, Graph},
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+};
 use anyhow::Result;
 
-pub async fn get_node_scores(
+/// Fee rate assumed for nodes not reporting it, the default of fiber nodes
+const DEFAULT_FEE_RATE: u64 = 1000;
+/// A hop costs the same as this fee rate, it keeps free channels from costing nothing
+const HOP_FEE_RATE: f64 = 1000.0;
+/// Relative tolerance of equal path costs
+const COST_EPSILON: f64 = 1e-9;
+/// Failure probability of `CentralitySampling::ErrorBound`
+const ERROR_BOUND_DELTA: f64 = 0.1;
+
+/// Max centralities kept in `CACHE`
+const MAX_CACHED: usize = 8;
+
+/// Centrality of recent graphs, reused while a graph fingerprint is unchanged.
+/// Agents of different tokens see different graphs, the least recently used entry is dropped.
+static CACHE: Mutex<Vec<(CacheKey, Arc<BetweennessCentrality>)>> = Mutex::new(Vec::new());
+
+#[derive(PartialEq)]
+struct CacheKey {
+    fingerprint: u64,
+    path_cost: PathCost,
+    sampling: CentralitySampling,
+    /// Seed of sampled pivots, `None` for exact centrality
+    seed: Option<u64>,
+}
+
+/// Betweenness centrality of candidates
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Centrality {
+    pub path_cost: PathCost,
+    pub sampling: CentralitySampling,
+}
+
+impl Heuristic for Centrality {
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        // pivots of an unchanged graph stay the same across rounds so the cache is reused
+        let seed = context.fixed_seed.unwrap_or_else(|| graph.fingerprint());
+        Box::pin(get_node_scores(
+            graph,
+            nodes,
+            self.path_cost,
+            self.sampling,
+            seed,
+        ))
+    }
+}
+
+async fn get_node_scores(
     graph: Arc<Graph>,
     nodes: HashSet<PeerId>,
+    path_cost: PathCost,
+    sampling: CentralitySampling,
+    seed: u64,
 ) -> Result<HashMap<PeerId, f64>> {
-    let bc = BetweennessCentrality::build(graph).await?;
+    let bc = BetweennessCentrality::cached(graph, path_cost, sampling, seed).await?;
     let centrality = bc.get(true);
     let scores = nodes
         .into_iter()
@@ -35,11 +98,76 @@ pub struct BetweennessCentrality {
 }
 
 impl BetweennessCentrality {
+    /// Exact centrality over shortest paths by hop count
     pub async fn build(graph: Arc<Graph>) -> Result<Self> {
-        // compute centrality for all ndoes
-        let tasks = (0..graph.nodes().len()).map(|id| {
+        Self::build_with(graph, PathCost::Hop, CentralitySampling::Exact, 0).await
+    }
+
+    /// Build or reuse the centrality of a graph with the same fingerprint, sampled centrality
+    /// is only reused with the same `seed` so results are reproducible
+    pub async fn cached(
+        graph: Arc<Graph>,
+        path_cost: PathCost,
+        sampling: CentralitySampling,
+        seed: u64,
+    ) -> Result<Arc<Self>> {
+        let key = CacheKey {
+            fingerprint: graph.fingerprint(),
+            path_cost,
+            sampling,
+            seed: (sampling != CentralitySampling::Exact).then_some(seed),
+        };
+        {
+            let mut cache = CACHE.lock().expect("lock");
+            if let Some(i) = cache.iter().position(|(k, _)| k == &key) {
+                debug!(
+                    "Reuse centrality of graph {:x} {path_cost:?} {sampling:?}",
+                    key.fingerprint
+                );
+                // keep the most recently used entry last
+                let entry = cache.remove(i);
+                let bc = Arc::clone(&entry.1);
+                cache.push(entry);
+                return Ok(bc);
+            }
+        }
+
+        let bc = Arc::new(Self::build_with(graph, path_cost, sampling, seed).await?);
+        let mut cache = CACHE.lock().expect("lock");
+        if !cache.iter().any(|(k, _)| k == &key) {
+            if cache.len() >= MAX_CACHED {
+                cache.remove(0);
+            }
+            cache.push((key, Arc::clone(&bc)));
+        }
+        Ok(bc)
+    }
+
+    /// Centrality over shortest paths by `path_cost` from sources sampled with `seed`
+    pub async fn build_with(
+        graph: Arc<Graph>,
+        path_cost: PathCost,
+        sampling: CentralitySampling,
+        seed: u64,
+    ) -> Result<Self> {
+        let costs = edge_costs(&graph, path_cost).map(Arc::new);
+        let n = graph.nodes().len();
+        let pivots = sample_pivots(n, sampling, seed);
+        // sum of sampled dependencies is scaled to all sources
+        let scale = n as f64 / pivots.len().max(1) as f64;
+        debug!(
+            "Compute centrality of {n} nodes from {} sources {path_cost:?}",
+            pivots.len()
+        );
+
+        // compute centrality for sampled nodes
+        let tasks = pivots.into_iter().map(|id| {
             let graph = Arc::clone(&graph);
-            tokio::task::spawn_blocking(move || centrality(graph.nodes(), graph.edges(), id))
+            let costs = costs.clone();
+            tokio::task::spawn_blocking(move || match costs {
+                Some(costs) => weighted_centrality(&costs, id),
+                None => centrality(graph.edges(), id),
+            })
         });
 
         // Aggregate centrality
@@ -50,7 +178,7 @@ impl BetweennessCentrality {
             let p = task.await?;
             debug_assert_eq!(p.len(), graph.nodes().len(), "partial len");
             for (n_idx, c) in p.into_iter().enumerate() {
-                centrality[n_idx] += c;
+                centrality[n_idx] += c * scale;
             }
         }
 
@@ -84,10 +212,14 @@ impl BetweennessCentrality {
         })
     }
 
-    /// Normalize centrality to 0.0 ~ 1.0 if normalize is passed
+    /// Normalize centrality to 0.0 ~ 1.0 if normalize is passed,
+    /// all nodes get 0.0 if they have the same centrality
     pub fn get(&self, normalize: bool) -> HashMap<PeerId, f64> {
-        assert!(self.max - self.min > 0.0);
-        let z = 1.0 / (self.max - self.min);
+        let z = if self.max > self.min {
+            1.0 / (self.max - self.min)
+        } else {
+            0.0
+        };
 
         let mut centrality = HashMap::with_capacity(self.centrality.len());
 
@@ -109,17 +241,16 @@ impl BetweennessCentrality {
 //
 // # Arguments
 //
-// - nodes: all nodes in the network
 // - edges: node edges
 // - s: the start node
 //
-fn centrality(nodes: &[NodeInfo], edges: &[Vec<usize>], s: usize) -> Vec<f64> {
-    let mut centrality: Vec<f64> = vec![0.0; nodes.len()];
+pub(super) fn centrality(edges: &[Vec<usize>], s: usize) -> Vec<f64> {
+    let mut centrality: Vec<f64> = vec![0.0; edges.len()];
     // distance from s to node v
-    let mut dist: Vec<i32> = vec![-1; nodes.len()];
+    let mut dist: Vec<i32> = vec![-1; edges.len()];
     // precede shortest path list from s to t
-    let mut pred: Vec<Vec<usize>> = vec![Vec::default(); nodes.len()];
-    let mut sigma: Vec<usize> = vec![0; nodes.len()];
+    let mut pred: Vec<Vec<usize>> = vec![Vec::default(); edges.len()];
+    let mut sigma: Vec<usize> = vec![0; edges.len()];
 
     let mut queue = VecDeque::default();
     let mut stack = VecDeque::default();
@@ -143,7 +274,7 @@ fn centrality(nodes: &[NodeInfo], edges: &[Vec<usize>], s: usize) -> Vec<f64> {
         }
     }
 
-    let mut delta: Vec<f64> = vec![0.0; nodes.len()];
+    let mut delta: Vec<f64> = vec![0.0; edges.len()];
 
     while let Some(w) = stack.pop_back() {
         for v in pred[w].clone() {
@@ -156,3 +287,229 @@ fn centrality(nodes: &[NodeInfo], edges: &[Vec<usize>], s: usize) -> Vec<f64> {
 
     centrality
 }
+
+/// Source nodes of shortest paths in a graph of `n` nodes, the same `seed` picks the same nodes
+pub(super) fn sample_pivots(n: usize, sampling: CentralitySampling, seed: u64) -> Vec<usize> {
+    match sampling.pivots(n) {
+        Some(k) if k < n => sample(&mut StdRng::seed_from_u64(seed), n, k).into_vec(),
+        _ => (0..n).collect(),
+    }
+}
+
+impl CentralitySampling {
+    /// Number of source pivots in a graph of `n` nodes, `None` for all nodes
+    fn pivots(&self, n: usize) -> Option<usize> {
+        match *self {
+            Self::Exact => None,
+            Self::Pivots { k } => Some(k.max(1)),
+            // Hoeffding bound on dependencies normalized by n - 2, with a union bound over nodes
+            Self::ErrorBound { epsilon } if epsilon > 0.0 => {
+                let k = (2.0 * n as f64 / ERROR_BOUND_DELTA).ln() / (2.0 * epsilon * epsilon);
+                Some(k.ceil() as usize)
+            }
+            Self::ErrorBound { .. } => None,
+        }
+    }
+}
+
+/// Cost of each channel edge, `None` for hop count which runs the BFS variant
+fn edge_costs(graph: &Graph, path_cost: PathCost) -> Option<Vec<Vec<(usize, f64)>>> {
+    let max_capacity = graph
+        .channel_edges()
+        .iter()
+        .flatten()
+        .map(|e| e.capacity)
+        .max()
+        .unwrap_or_default()
+        .max(1);
+    let cost = |e: &Edge| match path_cost {
+        PathCost::Hop => 1.0,
+        PathCost::Fee => 1.0 + e.fee_rate.unwrap_or(DEFAULT_FEE_RATE) as f64 / HOP_FEE_RATE,
+        // the largest channel costs one
+        PathCost::Capacity => max_capacity as f64 / e.capacity.max(1) as f64,
+    };
+    if path_cost == PathCost::Hop {
+        return None;
+    }
+    let costs = graph
+        .channel_edges()
+        .iter()
+        .map(|edges| edges.iter().map(|e| (e.to, cost(e))).collect())
+        .collect();
+    Some(costs)
+}
+
+/// Path cost ordered for the Dijkstra heap
+#[derive(PartialEq)]
+struct Cost(f64);
+
+impl Eq for Cost {}
+
+impl Ord for Cost {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl PartialOrd for Cost {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn same_cost(a: f64, b: f64) -> bool {
+    (a - b).abs() <= COST_EPSILON * a.abs().max(b.abs())
+}
+
+// Brandes algorithm with Dijkstra, edge costs must be positive
+//
+// # Arguments
+//
+// - edges: node edges with costs
+// - s: the start node
+//
+fn weighted_centrality(edges: &[Vec<(usize, f64)>], s: usize) -> Vec<f64> {
+    let mut centrality: Vec<f64> = vec![0.0; edges.len()];
+    // cost from s to node v
+    let mut dist: Vec<Option<f64>> = vec![None; edges.len()];
+    // precede shortest path list from s to t
+    let mut pred: Vec<Vec<usize>> = vec![Vec::default(); edges.len()];
+    let mut sigma: Vec<f64> = vec![0.0; edges.len()];
+    let mut settled: Vec<bool> = vec![false; edges.len()];
+
+    let mut heap = BinaryHeap::default();
+    // nodes in order of non-decreasing cost
+    let mut stack = Vec::default();
+
+    // start with s
+    dist[s] = Some(0.0);
+    sigma[s] = 1.0;
+    heap.push(Reverse((Cost(0.0), s)));
+
+    while let Some(Reverse((Cost(d), v))) = heap.pop() {
+        if settled[v] {
+            continue;
+        }
+        settled[v] = true;
+        stack.push(v);
+        for &(w, cost) in &edges[v] {
+            if settled[w] {
+                continue;
+            }
+            let alt = d + cost;
+            match dist[w] {
+                Some(dw) if same_cost(alt, dw) => {
+                    sigma[w] += sigma[v];
+                    pred[w].push(v);
+                }
+                Some(dw) if alt > dw => {}
+                _ => {
+                    dist[w] = Some(alt);
+                    sigma[w] = sigma[v];
+                    pred[w] = vec![v];
+                    heap.push(Reverse((Cost(alt), w)));
+                }
+            }
+        }
+    }
+
+    let mut delta: Vec<f64> = vec![0.0; edges.len()];
+
+    while let Some(w) = stack.pop() {
+        for &v in &pred[w] {
+            delta[v] += (sigma[v] / sigma[w]) * (1.0 + delta[w]);
+        }
+        if w != s {
+            centrality[w] += delta[w];
+        }
+    }
+
+    centrality
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{centrality, weighted_centrality, Centrality, CACHE, HOP_FEE_RATE};
+    use crate::{
+        config::{CentralitySampling, PathCost},
+        testing::{channel, context, graph, node, peer},
+        traits::{Heuristic, HeuristicContext},
+    };
+
+    /// Undirected adjacency of `n` nodes
+    fn edges(n: usize, links: &[(usize, usize)]) -> Vec<Vec<usize>> {
+        let mut edges = vec![Vec::default(); n];
+        for &(a, b) in links {
+            edges[a].push(b);
+            edges[b].push(a);
+        }
+        edges
+    }
+
+    #[test]
+    fn test_weighted_centrality_unit_costs() {
+        // two shortest paths from 0 to 3 and a tail 3 - 4 - 5
+        let edges = edges(6, &[(0, 1), (0, 2), (1, 3), (2, 3), (3, 4), (4, 5), (1, 2)]);
+        let costs: Vec<Vec<(usize, f64)>> = edges
+            .iter()
+            .map(|e| e.iter().map(|&w| (w, 1.0)).collect())
+            .collect();
+        for s in 0..edges.len() {
+            let expected = centrality(&edges, s);
+            let actual = weighted_centrality(&costs, s);
+            for (e, a) in expected.iter().zip(actual) {
+                assert!((e - a).abs() < 1e-9, "source {s} {expected:?} {a}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_weighted_centrality_costs() {
+        // 0 reaches 3 through 1 or 2 in two hops, 2 charges a high fee
+        let edges = edges(4, &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+        let by_hop = centrality(&edges, 0);
+        assert_eq!(by_hop, vec![0.0, 0.5, 0.5, 0.0]);
+
+        let fee = |fee_rate: f64| 1.0 + fee_rate / HOP_FEE_RATE;
+        let costs = vec![
+            vec![(1, fee(1000.0)), (2, fee(5000.0))],
+            vec![(0, fee(1000.0)), (3, fee(1000.0))],
+            vec![(0, fee(5000.0)), (3, fee(5000.0))],
+            vec![(1, fee(1000.0)), (2, fee(5000.0))],
+        ];
+        // the cheap path through 1 carries all the flow
+        assert_eq!(weighted_centrality(&costs, 0), vec![0.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[tokio::test]
+    async fn test_sampled_centrality_cache() {
+        let channels = (0..6).map(|i| channel(i, i, (i + 1) % 6)).collect();
+        let graph = Arc::new(graph((0..6).map(node).collect(), channels));
+        let sampling = CentralitySampling::Pivots { k: 3 };
+        let heuristic = Centrality {
+            path_cost: PathCost::Hop,
+            sampling,
+        };
+
+        // rounds without a configured seed draw different seeds
+        let mut rounds = Vec::default();
+        for seed in [1, 2] {
+            let context = HeuristicContext { seed, ..context() };
+            let scores = heuristic
+                .get_node_scores(Arc::clone(&graph), (1..6).map(peer).collect(), &context)
+                .await
+                .expect("scores");
+            rounds.push((1..6).map(|i| scores[&peer(i)]).collect::<Vec<_>>());
+        }
+        assert_eq!(rounds[0], rounds[1]);
+        let cached = CACHE
+            .lock()
+            .expect("lock")
+            .iter()
+            .filter(|(k, _)| k.fingerprint == graph.fingerprint() && k.sampling == sampling)
+            .count();
+        assert_eq!(cached, 1);
+    }
+}
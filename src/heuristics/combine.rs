@@ -3,42 +3,203 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use fnn::rpc::peer::PeerId;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{Heuristic, HeuristicConfig},
+    config::{Combiner, HeuristicConfig},
     graph::Graph,
+    traits::HeuristicContext,
 };
 
+use super::registry::build_heuristic;
+
+/// Score given by a heuristic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeuristicScore {
+    /// Name of the heuristic
+    pub heuristic: String,
+    pub weight: f32,
+    pub score: f64,
+}
+
+/// Weighted score of a node and the scores it combined from
+#[derive(Debug, Clone)]
+pub struct NodeScore {
+    pub score: f64,
+    pub details: Vec<HeuristicScore>,
+}
+
+/// Check heuristics, normalization and the combiner of the config
+pub fn validate(config: &HeuristicConfig) -> Result<()> {
+    for h in &config.heuristics {
+        build_heuristic(h)?;
+        h.normalize
+            .validate()
+            .with_context(|| format!("normalize of heuristic {}", h.heuristic))?;
+        for t in &h.transforms {
+            t.validate()
+                .with_context(|| format!("transforms of heuristic {}", h.heuristic))?;
+        }
+    }
+    match config.combine {
+        Combiner::WeightedSum => {}
+        Combiner::WeightedProduct => {
+            if let Some(h) = config.heuristics.iter().find(|h| h.weight < 0.0) {
+                bail!(
+                    "WeightedProduct requires non-negative weights, heuristic {} has {}",
+                    h.heuristic,
+                    h.weight
+                );
+            }
+        }
+        Combiner::ReciprocalRank { k } => {
+            if !k.is_finite() || k < 0.0 {
+                bail!("k of ReciprocalRank must be non-negative, got {k}");
+            }
+        }
+    }
+    Ok(())
+}
+
 pub async fn get_node_scores(
     config: &HeuristicConfig,
     graph: Arc<Graph>,
     nodes: HashSet<PeerId>,
-) -> Result<HashMap<PeerId, f64>> {
+    context: &HeuristicContext,
+) -> Result<HashMap<PeerId, NodeScore>> {
     let mut sub_scores: Vec<HashMap<PeerId, f64>> = Default::default();
     for h in config.heuristics.iter() {
-        let s = match h.heuristic {
-            Heuristic::Centrality => {
-                super::centrality::get_node_scores(graph.clone(), nodes.clone()).await?
-            }
-            Heuristic::Random => {
-                super::random::get_node_scores(graph.clone(), nodes.clone()).await?
-            }
-            Heuristic::Richness => {
-                super::richness::get_node_scores(graph.clone(), nodes.clone()).await?
-            }
-        };
+        let heuristic = build_heuristic(h)?;
+        let s = heuristic
+            .get_node_scores(graph.clone(), nodes.clone(), context)
+            .await
+            .with_context(|| format!("heuristic {}", h.heuristic))?;
+        // missing candidates count zero
+        let mut s: HashMap<PeerId, f64> = nodes
+            .iter()
+            .map(|n| (n.clone(), s.get(n).cloned().unwrap_or_default()))
+            .collect();
+        h.normalize.apply(&mut s);
+        for score in s.values_mut() {
+            *score = h.transforms.iter().fold(*score, |score, t| t.apply(score));
+        }
         sub_scores.push(s);
     }
 
-    let mut scores: HashMap<PeerId, f64> = Default::default();
+    // 1-based ranks of each heuristic, ties share the best rank
+    let ranks: Vec<HashMap<PeerId, usize>> = match config.combine {
+        Combiner::ReciprocalRank { .. } => sub_scores.iter().map(ranks).collect(),
+        _ => Default::default(),
+    };
+    let total_weight: f64 = config.heuristics.iter().map(|h| h.weight as f64).sum();
+
+    let mut scores: HashMap<PeerId, NodeScore> = Default::default();
     for n in nodes {
-        let mut s = 0.0;
+        let mut details = Vec::with_capacity(config.heuristics.len());
         for (i, h) in config.heuristics.iter().enumerate() {
-            s += sub_scores[i][&n] * h.weight as f64;
+            details.push(HeuristicScore {
+                heuristic: h.heuristic.clone(),
+                weight: h.weight,
+                score: sub_scores[i][&n],
+            });
         }
-        scores.insert(n, s);
+        let s = match config.combine {
+            Combiner::WeightedSum => details.iter().map(|d| d.score * d.weight as f64).sum(),
+            Combiner::WeightedProduct => {
+                if total_weight > 0.0 {
+                    details
+                        .iter()
+                        .map(|d| d.score.max(0.0).powf(d.weight as f64 / total_weight))
+                        .product()
+                } else {
+                    0.0
+                }
+            }
+            Combiner::ReciprocalRank { k } => details
+                .iter()
+                .enumerate()
+                .map(|(i, d)| d.weight as f64 / (k + ranks[i][&n] as f64))
+                .sum(),
+        };
+        scores.insert(n, NodeScore { score: s, details });
     }
     Ok(scores)
 }
+
+/// Rank by descending score, equal scores share the best rank
+fn ranks(scores: &HashMap<PeerId, f64>) -> HashMap<PeerId, usize> {
+    let mut sorted: Vec<f64> = scores.values().cloned().collect();
+    sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+    scores
+        .iter()
+        .map(|(n, s)| (n.clone(), sorted.partition_point(|v| v > s) + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use fnn::rpc::peer::PeerId;
+    use serde_json::json;
+
+    use super::{get_node_scores, validate};
+    use crate::{
+        config::{Combiner, HeuristicConfig},
+        graph::Graph,
+        testing::{context, peer, register_fixed},
+    };
+
+    #[tokio::test]
+    async fn test_normalize_and_combine() {
+        register_fixed();
+        // a is 0.75 0.5 0.0 1.0 after MinMax and Invert, b is 0.5 for all by Rank
+        let mut config: HeuristicConfig = serde_json::from_value(json!({
+            "heuristics": [
+                {
+                    "heuristic": "Fixed",
+                    "weight": 1.0,
+                    "scores": [[1, 0.2], [2, 0.4], [3, 0.8]],
+                    "normalize": { "type": "MinMax" },
+                    "transforms": [{ "type": "Invert" }],
+                },
+                {
+                    "heuristic": "Fixed",
+                    "weight": 1.0,
+                    "scores": [[1, 0.5], [2, 0.5], [3, 0.5], [4, 0.5]],
+                    "normalize": { "type": "Rank" },
+                },
+            ],
+        }))
+        .expect("config");
+        validate(&config).expect("validate");
+        let graph = Arc::new(Graph::build(Vec::default(), Vec::default()));
+        let nodes: HashSet<PeerId> = (1..5).map(peer).collect();
+        let mut combine = |combine: Combiner| {
+            config.combine = combine;
+            let config = config.clone();
+            let graph = graph.clone();
+            let nodes = nodes.clone();
+            async move {
+                let scores = get_node_scores(&config, graph, nodes, &context())
+                    .await
+                    .expect("scores");
+                (1..5).map(|i| scores[&peer(i)].score).collect::<Vec<_>>()
+            }
+        };
+
+        let sum = combine(Combiner::WeightedSum).await;
+        let product = combine(Combiner::WeightedProduct).await;
+        let rrf = combine(Combiner::ReciprocalRank { k: 0.0 }).await;
+
+        assert_eq!(sum, vec![1.25, 1.0, 0.5, 1.5]);
+        // a zero score vetoes the candidate
+        assert_eq!(product[2], 0.0);
+        assert!((product[3] - 0.5f64.sqrt()).abs() < 1e-9);
+        assert!(product[3] > product[0] && product[0] > product[1]);
+        // ranks of a are 2 3 4 1, all rank 1 in b
+        assert_eq!(rrf, vec![1.5, 1.0 + 1.0 / 3.0, 1.25, 2.0]);
+    }
+}
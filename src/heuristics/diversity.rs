@@ -0,0 +1,147 @@
+//! Score candidates by how much of their neighbourhood is new to us
+//!
+//! Our coverage is the 1- and 2-hop neighbourhood of our local channels. A candidate's
+//! neighbourhood is itself and its 1-hop neighbours weighing 1.0 plus its 2-hop
+//! neighbours weighing 0.5, the score is the uncovered fraction of it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use fnn::{fiber::types::Pubkey, rpc::peer::PeerId};
+use serde::Deserialize;
+
+use crate::{
+    graph::Graph,
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+};
+
+/// Weight of a 2-hop neighbour relative to a 1-hop neighbour
+const TWO_HOP_WEIGHT: f64 = 0.5;
+
+/// Part of the node's 1- and 2-hop neighbourhood not covered by our local channels
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Diversity {}
+
+impl Heuristic for Diversity {
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        Box::pin(get_node_scores(
+            graph,
+            nodes,
+            context.self_id,
+            &context.local_peers,
+        ))
+    }
+}
+
+async fn get_node_scores(
+    graph: Arc<Graph>,
+    nodes: HashSet<PeerId>,
+    self_id: Pubkey,
+    local_peers: &HashSet<PeerId>,
+) -> Result<HashMap<PeerId, f64>> {
+    let peers: Vec<PeerId> = graph
+        .nodes()
+        .iter()
+        .map(|n| PeerId::from_public_key(&n.node_id.into()))
+        .collect();
+    let edges = graph.edges();
+
+    // local channels may be private, so take our neighbours from both
+    let mut neighbours: HashSet<usize> = peers
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| local_peers.contains(p))
+        .map(|(i, _)| i)
+        .collect();
+    let s = graph.node_index(&self_id);
+    if let Some(s) = s {
+        neighbours.extend(edges[s].iter().cloned());
+    }
+    let mut covered: HashSet<usize> = s.into_iter().collect();
+    for &v in &neighbours {
+        covered.insert(v);
+        covered.extend(edges[v].iter().cloned());
+    }
+
+    let scores = peers
+        .into_iter()
+        .enumerate()
+        .filter(|(_, peer)| nodes.contains(peer))
+        .map(|(c, peer)| (peer, uncovered(edges, c, &covered)))
+        .collect();
+    Ok(scores)
+}
+
+/// Uncovered fraction of the neighbourhood of `c`
+fn uncovered(edges: &[Vec<usize>], c: usize, covered: &HashSet<usize>) -> f64 {
+    let one_hop: HashSet<usize> = edges[c].iter().cloned().chain([c]).collect();
+    let two_hop: HashSet<usize> = one_hop
+        .iter()
+        .flat_map(|v| edges[*v].iter().cloned())
+        .filter(|v| !one_hop.contains(v))
+        .collect();
+
+    let weight = |set: &HashSet<usize>, w: f64| {
+        let total = set.len() as f64 * w;
+        let new = set.iter().filter(|v| !covered.contains(v)).count() as f64 * w;
+        (new, total)
+    };
+    let (new1, total1) = weight(&one_hop, 1.0);
+    let (new2, total2) = weight(&two_hop, TWO_HOP_WEIGHT);
+    (new1 + new2) / (total1 + total2)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Diversity;
+    use crate::{
+        testing::{channel, context, graph, node, peer},
+        traits::{Heuristic, HeuristicContext},
+    };
+
+    #[tokio::test]
+    async fn test_uncovered_ordering() {
+        // we cover 0 - 1 - 2 of the line 0 - 1 - 2 - 3, the star 4 - 5, 4 - 6 is unreached
+        let channels = vec![
+            channel(0, 0, 1),
+            channel(1, 1, 2),
+            channel(2, 2, 3),
+            channel(3, 4, 5),
+            channel(4, 4, 6),
+        ];
+        let graph = Arc::new(graph((0..7).map(node).collect(), channels));
+        let scores = |context: HeuristicContext| {
+            let graph = Arc::clone(&graph);
+            async move {
+                let scores = Diversity {}
+                    .get_node_scores(graph, [2, 3, 4].map(peer).into(), &context)
+                    .await
+                    .expect("scores");
+                [2, 3, 4].map(|i| scores[&peer(i)])
+            }
+        };
+
+        let [s2, s3, s4] = scores(context()).await;
+        assert_eq!(s4, 1.0);
+        assert!(s4 > s3 && s3 > s2, "{:?}", [s2, s3, s4]);
+
+        // a private channel with 5 covers the star
+        let context = HeuristicContext {
+            local_peers: [peer(5)].into(),
+            ..context()
+        };
+        let [s2, s3, s4] = scores(context).await;
+        assert!(s3 > s4 && s4 > s2, "{:?}", [s2, s3, s4]);
+    }
+}
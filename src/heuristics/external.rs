@@ -0,0 +1,272 @@
+//! Scores supplied by the operator
+//!
+//! Scores are read from a JSON or CSV file, or from the stdout of a command, on every round.
+//! JSON is an object of peer to score, CSV has a `peer,score` line for each peer. A peer is
+//! a peer id or a node pubkey in hex, a score must be within 0.0 ~ 1.0. Malformed entries are
+//! skipped with a warning, unlisted peers get `default_score`. A command running longer than
+//! `timeout` seconds is killed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fnn::{fiber::types::Pubkey, rpc::peer::PeerId};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::{
+    config::HeuristicParams,
+    graph::Graph,
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScoreFormat {
+    Json,
+    Csv,
+}
+
+/// Scores supplied by the operator, either `path` or `command` must be set
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct External {
+    /// JSON or CSV file, the format is taken from the extension if not set
+    pub path: Option<PathBuf>,
+    /// Program and arguments printing scores to stdout
+    pub command: Option<Vec<String>>,
+    /// Format of the scores, defaults to JSON for commands
+    pub format: Option<ScoreFormat>,
+    /// Score of unlisted peers
+    #[serde(default)]
+    pub default_score: f64,
+    /// Seconds a command may run
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+impl External {
+    /// Deserialize and validate params
+    pub fn from_params(params: &HeuristicParams) -> Result<Arc<dyn Heuristic>> {
+        let external: Self = serde_json::from_value(Value::Object(params.clone()))?;
+        match (&external.path, &external.command) {
+            (Some(_), Some(_)) => bail!("only one of path and command can be set"),
+            (None, None) => bail!("path or command must be set"),
+            (None, Some(command)) if command.is_empty() => bail!("command is empty"),
+            _ => {}
+        }
+        if !(0.0..=1.0).contains(&external.default_score) {
+            bail!(
+                "default_score {} is out of 0.0 ~ 1.0",
+                external.default_score
+            );
+        }
+        if external.timeout == 0 {
+            bail!("timeout must be positive");
+        }
+        Ok(Arc::new(external))
+    }
+
+    fn format(&self) -> ScoreFormat {
+        if let Some(format) = self.format {
+            return format;
+        }
+        match self.path.as_deref().and_then(Path::extension) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => ScoreFormat::Csv,
+            _ => ScoreFormat::Json,
+        }
+    }
+
+    /// Read the raw scores
+    async fn read(&self) -> Result<String> {
+        if let Some(path) = &self.path {
+            return tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("read scores {}", path.display()));
+        }
+        let command = self.command.as_deref().unwrap_or_default();
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| anyhow!("command is empty"))?;
+        // the child is killed once its output future is dropped on timeout
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("run score command {program}"))?;
+        let output =
+            tokio::time::timeout(Duration::from_secs(self.timeout), child.wait_with_output())
+                .await
+                .map_err(|_| anyhow!("score command {program} timed out after {}s", self.timeout))?
+                .with_context(|| format!("run score command {program}"))?;
+        if !output.status.success() {
+            bail!(
+                "score command {program} exited with {} {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        String::from_utf8(output.stdout).context("score command output is not UTF-8")
+    }
+
+    async fn load(&self) -> Result<HashMap<PeerId, f64>> {
+        let raw = self.read().await?;
+        let entries: Vec<(String, Value)> = match self.format() {
+            ScoreFormat::Json => {
+                let value: Value = serde_json::from_str(&raw).context("parse JSON scores")?;
+                let Value::Object(map) = value else {
+                    bail!("JSON scores must be an object of peer to score");
+                };
+                map.into_iter().collect()
+            }
+            ScoreFormat::Csv => raw
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter(|line| !line.eq_ignore_ascii_case("peer,score"))
+                .map(|line| match line.split_once(',') {
+                    Some((peer, score)) => (
+                        peer.trim().to_string(),
+                        score
+                            .trim()
+                            .parse::<f64>()
+                            .map(Value::from)
+                            .unwrap_or(Value::Null),
+                    ),
+                    None => (line.to_string(), Value::Null),
+                })
+                .collect(),
+        };
+
+        let mut scores: HashMap<PeerId, f64> = HashMap::with_capacity(entries.len());
+        for (peer, score) in entries {
+            let Some(peer_id) = parse_peer(&peer) else {
+                warn!("Skipping external score of malformed peer {peer}");
+                continue;
+            };
+            let Some(score) = score.as_f64().filter(|s| (0.0..=1.0).contains(s)) else {
+                warn!("Skipping external score {score} of {peer}, expect 0.0 ~ 1.0");
+                continue;
+            };
+            if scores.insert(peer_id, score).is_some() {
+                warn!("Duplicated external score of {peer}, the last one is used");
+            }
+        }
+        debug!("Load {} external scores", scores.len());
+        Ok(scores)
+    }
+}
+
+/// Parse a peer id or a node pubkey
+fn parse_peer(s: &str) -> Option<PeerId> {
+    if let Ok(peer) = s.parse::<PeerId>() {
+        return Some(peer);
+    }
+    let pubkey: Pubkey =
+        serde_json::from_value(Value::String(s.trim_start_matches("0x").into())).ok()?;
+    Some(PeerId::from_public_key(&pubkey.into()))
+}
+
+impl Heuristic for External {
+    fn get_node_scores<'a>(
+        &'a self,
+        _graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        _context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        Box::pin(async move {
+            let scores = self.load().await?;
+            let scores = nodes
+                .into_iter()
+                .map(|peer| {
+                    let s = scores.get(&peer).cloned().unwrap_or(self.default_score);
+                    (peer, s)
+                })
+                .collect();
+            Ok(scores)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use fnn::rpc::peer::PeerId;
+    use serde_json::{json, Value};
+
+    use super::External;
+    use crate::{
+        graph::Graph,
+        testing::{context, peer, PUBKEYS},
+        traits::Heuristic,
+    };
+
+    async fn scores(params: Value, nodes: HashSet<PeerId>) -> anyhow::Result<Vec<f64>> {
+        let params = params.as_object().cloned().expect("params");
+        let heuristic = External::from_params(&params)?;
+        let graph = Arc::new(Graph::build(Vec::default(), Vec::default()));
+        let scores = heuristic.get_node_scores(graph, nodes, &context()).await?;
+        Ok((1..5).map(|i| scores[&peer(i)]).collect())
+    }
+
+    #[tokio::test]
+    async fn test_external_scores() {
+        let path = std::env::temp_dir().join(format!("external-scores-{}.csv", std::process::id()));
+        let csv = format!(
+            "peer,score\n{},1.0\n{},0.5\n{},2.0\nnot-a-peer,1.0\n",
+            peer(3),
+            PUBKEYS[4],
+            peer(2)
+        );
+        std::fs::write(&path, csv).expect("write scores");
+        let params = json!({ "path": path, "default_score": 0.1 });
+        let from_file = scores(params, (1..5).map(peer).collect()).await;
+        std::fs::remove_file(&path).expect("remove scores");
+
+        // out of range and malformed entries are skipped, unlisted peers get the default
+        assert_eq!(from_file.expect("scores"), vec![0.1, 0.1, 1.0, 0.5]);
+
+        let stdout = json!({ peer(1).to_string(): 0.3, PUBKEYS[2]: 0.7 }).to_string();
+        let params = json!({ "command": ["echo", stdout] });
+        let from_command = scores(params, (1..5).map(peer).collect()).await;
+        assert_eq!(from_command.expect("scores"), vec![0.3, 0.7, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_external_params() {
+        let from_params =
+            |params: Value| External::from_params(params.as_object().expect("params")).map(|_| ());
+        assert!(from_params(json!({ "path": "scores.csv" })).is_ok());
+        assert!(from_params(json!({})).is_err());
+        assert!(from_params(json!({ "path": "scores.csv", "command": ["echo"] })).is_err());
+        assert!(from_params(json!({ "path": "scores.csv", "default_score": 2.0 })).is_err());
+        // misspelled keys are rejected instead of ignored
+        assert!(from_params(json!({ "path": "scores.csv", "timout": 5 })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_external_command_timeout() {
+        let params = json!({ "command": ["sleep", "10"], "timeout": 1 });
+        let err = scores(params, (1..5).map(peer).collect())
+            .await
+            .expect_err("timeout");
+        assert!(err.to_string().contains("timed out"), "{err:?}");
+
+        let params = json!({ "command": ["false"] });
+        assert!(scores(params, HashSet::default()).await.is_err());
+    }
+}
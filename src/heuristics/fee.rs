@@ -0,0 +1,152 @@
+//! Score candidates by the typical fee of routing through them
+//!
+//! The typical fee of a node is the median fee rate it charges on its channels. A node
+//! charging the network median scores 0.5, a free node scores 1.0.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use fnn::rpc::peer::PeerId;
+use serde::Deserialize;
+
+use crate::{
+    config::FeeOutliers,
+    graph::Graph,
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+};
+
+/// Typical fee rate charged by the node, cheap nodes score higher
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fee {
+    pub fee_outliers: FeeOutliers,
+}
+
+impl Heuristic for Fee {
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        _context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        Box::pin(get_node_scores(graph, nodes, self.fee_outliers))
+    }
+}
+
+async fn get_node_scores(
+    graph: Arc<Graph>,
+    nodes: HashSet<PeerId>,
+    outliers: FeeOutliers,
+) -> Result<HashMap<PeerId, f64>> {
+    // fee rates charged by each node on its channels, outliers are capped or dropped
+    let mut fee_rates: Vec<Vec<u64>> = vec![Vec::default(); graph.nodes().len()];
+    let mut reported: Vec<bool> = vec![false; graph.nodes().len()];
+    for (n_idx, edges) in graph.channel_edges().iter().enumerate() {
+        for fee_rate in edges.iter().filter_map(|e| e.fee_rate) {
+            reported[n_idx] = true;
+            let fee_rate = match outliers {
+                FeeOutliers::Keep => fee_rate,
+                FeeOutliers::Cap { max_fee_rate } => fee_rate.min(max_fee_rate),
+                FeeOutliers::Exclude { max_fee_rate } if fee_rate > max_fee_rate => continue,
+                FeeOutliers::Exclude { .. } => fee_rate,
+            };
+            fee_rates[n_idx].push(fee_rate);
+        }
+    }
+
+    let mut all: Vec<u64> = fee_rates.iter().flatten().cloned().collect();
+    let reference = median(&mut all).unwrap_or_default().max(1) as f64;
+
+    let mut scores: HashMap<PeerId, f64> = HashMap::with_capacity(nodes.len());
+    for (n_idx, mut fee_rates) in fee_rates.into_iter().enumerate() {
+        let peer = PeerId::from_public_key(&graph.nodes()[n_idx].node_id.into());
+        if !nodes.contains(&peer) {
+            continue;
+        }
+        let s = match median(&mut fee_rates) {
+            Some(fee_rate) => reference / (reference + fee_rate as f64),
+            // every reported fee rate is an excluded outlier
+            None if reported[n_idx] => 0.0,
+            // nothing reported, assume the network median
+            None => 0.5,
+        };
+        scores.insert(peer, s);
+    }
+
+    // candidates out of the graph are neutral
+    let scores = nodes
+        .into_iter()
+        .map(|peer| {
+            let s = scores.get(&peer).cloned().unwrap_or(0.5);
+            (peer, s)
+        })
+        .collect();
+    Ok(scores)
+}
+
+fn median(values: &mut [u64]) -> Option<u64> {
+    values.sort_unstable();
+    values.get(values.len() / 2).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::Fee;
+    use crate::{
+        config::FeeOutliers,
+        testing::{channel, context, graph, node, peer},
+        traits::Heuristic,
+    };
+
+    #[tokio::test]
+    async fn test_fee_outliers() {
+        // peers 1 ~ 3 charge 100, 1000 and an outlier 1000000 on their channels with node 0,
+        // peer 4 reports no fee rate
+        let channels = [Some(100), Some(1000), Some(1_000_000), None]
+            .into_iter()
+            .enumerate()
+            .map(|(i, fee_rate)| {
+                let mut c = channel(i, i + 1, 0);
+                if let Some(fee_rate) = fee_rate {
+                    c["fee_rate_of_node1"] = json!(format!("{fee_rate:#x}"));
+                }
+                c
+            })
+            .collect();
+        let graph = Arc::new(graph((0..5).map(node).collect(), channels));
+        let scores = |fee_outliers: FeeOutliers| {
+            let graph = Arc::clone(&graph);
+            async move {
+                let scores = Fee { fee_outliers }
+                    .get_node_scores(graph, (1..5).map(peer).collect(), &context())
+                    .await
+                    .expect("scores");
+                (1..5).map(|i| scores[&peer(i)]).collect::<Vec<_>>()
+            }
+        };
+
+        // the network median 1000 scores 0.5 and unreported fees are assumed the median
+        let keep = scores(FeeOutliers::Keep).await;
+        assert!(keep[0] > keep[1] && keep[1] > keep[2], "{keep:?}");
+        assert_eq!((keep[1], keep[3]), (0.5, 0.5));
+
+        // capped outliers still rank last but closer to the median
+        let cap = scores(FeeOutliers::Cap { max_fee_rate: 5000 }).await;
+        assert!(cap[0] > cap[1] && cap[1] > cap[2], "{cap:?}");
+        assert_eq!(cap[2], 1000.0 / 6000.0);
+        assert!(cap[2] > keep[2]);
+
+        // a node charging only outliers scores zero, the median is taken without them
+        let exclude = scores(FeeOutliers::Exclude { max_fee_rate: 5000 }).await;
+        assert_eq!(exclude[2], 0.0);
+        assert_eq!((exclude[1], exclude[3]), (0.5, 0.5));
+        assert!(exclude[0] > exclude[1], "{exclude:?}");
+    }
+}
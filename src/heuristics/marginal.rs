@@ -0,0 +1,189 @@
+//! Score candidates by how much a channel to them improves the centrality of our own node
+//!
+//! For each candidate a hypothetical self↔candidate edge is added to the graph and the
+//! centrality of the self node is measured again, the gain is normalized to 0.0 ~ 1.0.
+//!
+//! `Closeness` runs a BFS from the self node per candidate, O(C·E) for C candidates.
+//! `Betweenness` runs a BFS from every pivot per candidate, O(C·P·E) for P pivots which is
+//! O(C·V·E) when exact, so bound P with `sampling` on graphs of more than a few hundred nodes.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use fnn::{fiber::types::Pubkey, rpc::peer::PeerId};
+use serde::Deserialize;
+
+use crate::{
+    config::{CentralityMetric, CentralitySampling},
+    graph::Graph,
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+};
+
+use super::centrality::{centrality, sample_pivots};
+
+/// Centrality gained by our own node with a channel to the candidate
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarginalCentrality {
+    pub metric: CentralityMetric,
+    /// Sources of `Betweenness`, the cost grows with the number of pivots times candidates
+    pub sampling: CentralitySampling,
+}
+
+impl Heuristic for MarginalCentrality {
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        Box::pin(get_node_scores(
+            graph,
+            nodes,
+            context.self_id,
+            self.metric,
+            self.sampling,
+            context.seed,
+        ))
+    }
+}
+
+async fn get_node_scores(
+    graph: Arc<Graph>,
+    nodes: HashSet<PeerId>,
+    self_id: Pubkey,
+    metric: CentralityMetric,
+    sampling: CentralitySampling,
+    seed: u64,
+) -> Result<HashMap<PeerId, f64>> {
+    // the self node is isolated if it is not announced yet
+    let mut edges = graph.edges().to_vec();
+    let s = match graph.node_index(&self_id) {
+        Some(s) => s,
+        None => {
+            edges.push(Vec::default());
+            edges.len() - 1
+        }
+    };
+    let edges = Arc::new(edges);
+    // share pivots so gains of candidates are comparable
+    let pivots = Arc::new(sample_pivots(edges.len(), sampling, seed));
+
+    let base = {
+        let edges = Arc::clone(&edges);
+        let pivots = Arc::clone(&pivots);
+        tokio::task::spawn_blocking(move || self_centrality(&edges, s, metric, &pivots)).await?
+    };
+
+    let mut tasks = Vec::with_capacity(nodes.len());
+    for (index, node) in graph.nodes().iter().enumerate() {
+        let peer = PeerId::from_public_key(&node.node_id.into());
+        if index == s || !nodes.contains(&peer) {
+            continue;
+        }
+        let edges = Arc::clone(&edges);
+        let pivots = Arc::clone(&pivots);
+        let task = tokio::task::spawn_blocking(move || {
+            let mut edges = (*edges).clone();
+            edges[s].push(index);
+            edges[index].push(s);
+            self_centrality(&edges, s, metric, &pivots)
+        });
+        tasks.push((peer, task));
+    }
+
+    let mut gains: HashMap<PeerId, f64> = HashMap::with_capacity(nodes.len());
+    for (peer, task) in tasks {
+        let gain = (task.await? - base).max(0.0);
+        gains.insert(peer, gain);
+    }
+
+    let max = gains.values().cloned().fold(0.0, f64::max);
+    let scores = nodes
+        .into_iter()
+        .map(|peer| {
+            let gain = gains.get(&peer).cloned().unwrap_or_default();
+            let s = if max > 0.0 { gain / max } else { 0.0 };
+            (peer, s)
+        })
+        .collect();
+    Ok(scores)
+}
+
+/// Centrality of node `s` by hop count
+fn self_centrality(
+    edges: &[Vec<usize>],
+    s: usize,
+    metric: CentralityMetric,
+    pivots: &[usize],
+) -> f64 {
+    match metric {
+        CentralityMetric::Closeness => harmonic_closeness(edges, s),
+        CentralityMetric::Betweenness => pivots
+            .iter()
+            .filter(|p| **p != s)
+            .map(|p| centrality(edges, *p)[s])
+            .sum(),
+    }
+}
+
+/// Sum of inverse distances, unreachable nodes count zero
+fn harmonic_closeness(edges: &[Vec<usize>], s: usize) -> f64 {
+    let mut dist: Vec<Option<usize>> = vec![None; edges.len()];
+    let mut queue = VecDeque::default();
+    let mut closeness = 0.0;
+    dist[s] = Some(0);
+    queue.push_back(s);
+    while let Some(v) = queue.pop_front() {
+        let d = dist[v].unwrap_or_default() + 1;
+        for &w in &edges[v] {
+            if dist[w].is_none() {
+                dist[w] = Some(d);
+                closeness += 1.0 / d as f64;
+                queue.push_back(w);
+            }
+        }
+    }
+    closeness
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::MarginalCentrality;
+    use crate::{
+        config::{CentralityMetric, CentralitySampling},
+        testing::{channel, context, graph, node, peer},
+        traits::Heuristic,
+    };
+
+    #[tokio::test]
+    async fn test_bridge_beats_leaf() {
+        // the self node 0 reaches the leaf 2 through 1, the hub 3 of another component
+        // is unreachable
+        let channels = vec![
+            channel(0, 0, 1),
+            channel(1, 1, 2),
+            channel(2, 3, 4),
+            channel(3, 3, 5),
+            channel(4, 3, 6),
+        ];
+        let graph = Arc::new(graph((0..7).map(node).collect(), channels));
+        for metric in [CentralityMetric::Closeness, CentralityMetric::Betweenness] {
+            let heuristic = MarginalCentrality {
+                metric,
+                sampling: CentralitySampling::Exact,
+            };
+            let scores = heuristic
+                .get_node_scores(Arc::clone(&graph), [2, 3].map(peer).into(), &context())
+                .await
+                .expect("scores");
+            assert_eq!(scores[&peer(3)], 1.0, "{metric:?}");
+            assert!(scores[&peer(2)] < scores[&peer(3)], "{metric:?} {scores:?}");
+        }
+    }
+}
@@ -1,6 +1,24 @@
 mod centrality;
 mod combine;
+mod diversity;
+mod external;
+mod fee;
+mod marginal;
+mod normalize;
 mod random;
+mod registry;
 mod richness;
+mod stability;
 
-pub use combine::get_node_scores;
+pub use centrality::{BetweennessCentrality, Centrality};
+pub use combine::{get_node_scores, validate, HeuristicScore};
+pub use diversity::Diversity;
+pub use external::{External, ScoreFormat};
+pub use fee::Fee;
+pub use marginal::MarginalCentrality;
+pub use random::Random;
+pub use registry::{
+    build_heuristic, from_params, is_registered, register_heuristic, HeuristicFactory,
+};
+pub use richness::Richness;
+pub use stability::Stability;
@@ -0,0 +1,184 @@
+//! Normalization and transforms of the scores of a heuristic
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use fnn::rpc::peer::PeerId;
+
+use crate::config::{Normalization, Transform};
+
+impl Normalization {
+    pub fn validate(&self) -> Result<()> {
+        if let Self::ZScore { clip } = self {
+            if !clip.is_finite() || *clip <= 0.0 {
+                bail!("clip of ZScore must be positive, got {clip}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Normalize scores of all candidates in place
+    pub fn apply(&self, scores: &mut HashMap<PeerId, f64>) {
+        if scores.is_empty() {
+            return;
+        }
+        match *self {
+            Self::None => {}
+            Self::MinMax => {
+                let min = scores.values().cloned().fold(f64::INFINITY, f64::min);
+                let max = scores.values().cloned().fold(f64::NEG_INFINITY, f64::max);
+                for s in scores.values_mut() {
+                    *s = if max > min {
+                        (*s - min) / (max - min)
+                    } else {
+                        0.0
+                    };
+                }
+            }
+            Self::Rank => {
+                let mut sorted: Vec<f64> = scores.values().cloned().collect();
+                sorted.sort_unstable_by(f64::total_cmp);
+                let n = sorted.len();
+                for s in scores.values_mut() {
+                    // ties share the average of their 0-based ranks
+                    let score = *s;
+                    let first = sorted.partition_point(|v| *v < score);
+                    let last = sorted.partition_point(|v| *v <= score) - 1;
+                    *s = if n > 1 {
+                        (first + last) as f64 / 2.0 / (n - 1) as f64
+                    } else {
+                        1.0
+                    };
+                }
+            }
+            Self::ZScore { clip } => {
+                let n = scores.len() as f64;
+                let mean = scores.values().sum::<f64>() / n;
+                let std = (scores.values().map(|s| (s - mean).powi(2)).sum::<f64>() / n).sqrt();
+                for s in scores.values_mut() {
+                    let z = if std > 0.0 { (*s - mean) / std } else { 0.0 };
+                    *s = (z.clamp(-clip, clip) + clip) / (2.0 * clip);
+                }
+            }
+        }
+    }
+}
+
+impl Transform {
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::Invert => {}
+            Self::Power { exponent } => {
+                if !exponent.is_finite() || exponent <= 0.0 {
+                    bail!("exponent of Power must be positive, got {exponent}");
+                }
+            }
+            Self::Threshold { min } => {
+                if !min.is_finite() {
+                    bail!("min of Threshold must be finite, got {min}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, score: f64) -> f64 {
+        match *self {
+            Self::Invert => 1.0 - score,
+            // negative scores have no real power
+            Self::Power { exponent } => score.max(0.0).powf(exponent),
+            Self::Threshold { min } => {
+                if score < min {
+                    0.0
+                } else {
+                    score
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use fnn::rpc::peer::PeerId;
+
+    use crate::{
+        config::{Normalization, Transform},
+        testing::peer,
+    };
+
+    /// Normalized scores of peers 1 ~ n given `scores` in order
+    fn normalize(normalization: Normalization, scores: &[f64]) -> Vec<f64> {
+        let mut map: HashMap<PeerId, f64> = scores
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (peer(i + 1), *s))
+            .collect();
+        normalization.apply(&mut map);
+        (1..=scores.len()).map(|i| map[&peer(i)]).collect()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_normalization() {
+        let scores = [1.0, 2.0, 2.0, 5.0];
+        assert_eq!(normalize(Normalization::None, &scores), scores);
+        assert_eq!(
+            normalize(Normalization::MinMax, &scores),
+            [0.0, 0.25, 0.25, 1.0]
+        );
+        // ties share the average rank
+        assert_eq!(
+            normalize(Normalization::Rank, &scores),
+            [0.0, 0.5, 0.5, 1.0]
+        );
+        // mean 2.5 and std 1.5
+        assert_close(
+            &normalize(Normalization::ZScore { clip: 2.0 }, &scores),
+            &[0.25, 5.0 / 12.0, 5.0 / 12.0, 11.0 / 12.0],
+        );
+        assert_close(
+            &normalize(Normalization::ZScore { clip: 1.0 }, &scores),
+            &[0.0, 1.0 / 3.0, 1.0 / 3.0, 1.0],
+        );
+
+        // equal scores
+        let equal = [3.0, 3.0];
+        assert_eq!(normalize(Normalization::MinMax, &equal), [0.0, 0.0]);
+        assert_eq!(normalize(Normalization::Rank, &equal), [0.5, 0.5]);
+        assert_eq!(
+            normalize(Normalization::ZScore { clip: 3.0 }, &equal),
+            [0.5, 0.5]
+        );
+        assert_eq!(normalize(Normalization::Rank, &[3.0]), [1.0]);
+
+        assert!(Normalization::ZScore { clip: 0.0 }.validate().is_err());
+    }
+
+    #[test]
+    fn test_transforms() {
+        let threshold = Transform::Threshold { min: 0.5 };
+        assert_eq!(threshold.apply(0.4), 0.0);
+        assert_eq!(threshold.apply(0.5), 0.5);
+        let power = Transform::Power { exponent: 2.0 };
+        assert_eq!(power.apply(0.5), 0.25);
+        assert_eq!(power.apply(-1.0), 0.0);
+        assert_eq!(Transform::Invert.apply(0.25), 0.75);
+        // transforms apply in order
+        let transforms = [Transform::Invert, power, threshold];
+        let apply = |score: f64| transforms.iter().fold(score, |s, t| t.apply(s));
+        assert_eq!(apply(0.25), 0.5625);
+        assert_eq!(apply(0.5), 0.0);
+
+        assert!(Transform::Power { exponent: 0.0 }.validate().is_err());
+        assert!(Transform::Threshold { min: f64::NAN }.validate().is_err());
+    }
+}
@@ -5,15 +5,38 @@ use std::{
 
 use anyhow::Result;
 use fnn::rpc::peer::PeerId;
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
 
-use crate::graph::Graph;
+use crate::{
+    graph::Graph,
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+};
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Random {}
+
+impl Heuristic for Random {
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        Box::pin(get_node_scores(graph, nodes, context.seed))
+    }
+}
 
-pub async fn get_node_scores(
+async fn get_node_scores(
     _graph: Arc<Graph>,
     nodes: HashSet<PeerId>,
+    seed: u64,
 ) -> Result<HashMap<PeerId, f64>> {
-    let mut rng = rand::rng();
+    let mut rng = StdRng::seed_from_u64(seed);
+    // draw in a fixed order so scores only depend on the seed
+    let mut nodes: Vec<PeerId> = nodes.into_iter().collect();
+    nodes.sort_by_cached_key(|id| id.to_string());
     let scores = nodes
         .into_iter()
         .map(|id| (id, rng.random_range(0.0..=1.0)))
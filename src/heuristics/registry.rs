@@ -0,0 +1,135 @@
+//! Heuristics keyed by the name in config
+//!
+//! Built-in heuristics are registered on first use, downstream crates register their own
+//! with `register_heuristic` before agents start.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    config::{HeuristicItem, HeuristicParams},
+    traits::Heuristic,
+};
+
+use super::{
+    centrality::Centrality, diversity::Diversity, external::External, fee::Fee,
+    marginal::MarginalCentrality, random::Random, richness::Richness, stability::Stability,
+};
+
+/// Build a heuristic from its params in config
+pub type HeuristicFactory =
+    Arc<dyn Fn(&HeuristicParams) -> Result<Arc<dyn Heuristic>> + Send + Sync>;
+
+static REGISTRY: OnceLock<RwLock<HashMap<String, HeuristicFactory>>> = OnceLock::new();
+
+fn registry() -> &'static RwLock<HashMap<String, HeuristicFactory>> {
+    REGISTRY.get_or_init(|| {
+        let mut factories: HashMap<String, HeuristicFactory> = HashMap::default();
+        let mut builtin = |name: &str, factory: HeuristicFactory| {
+            factories.insert(name.to_string(), factory);
+        };
+        builtin("Random", from_params::<Random>());
+        builtin("Centrality", from_params::<Centrality>());
+        builtin("Richness", from_params::<Richness>());
+        builtin("MarginalCentrality", from_params::<MarginalCentrality>());
+        builtin("Stability", from_params::<Stability>());
+        builtin("Fee", from_params::<Fee>());
+        builtin("Diversity", from_params::<Diversity>());
+        builtin("External", Arc::new(External::from_params));
+        RwLock::new(factories)
+    })
+}
+
+/// Factory deserializing the heuristic from its params
+pub fn from_params<H: Heuristic + DeserializeOwned + 'static>() -> HeuristicFactory {
+    Arc::new(|params: &HeuristicParams| {
+        let heuristic: H = serde_json::from_value(Value::Object(params.clone()))?;
+        Ok(Arc::new(heuristic) as Arc<dyn Heuristic>)
+    })
+}
+
+/// Register a heuristic under `name`, a registered heuristic with the same name is replaced
+pub fn register_heuristic(name: &str, factory: HeuristicFactory) {
+    registry()
+        .write()
+        .expect("lock")
+        .insert(name.to_string(), factory);
+}
+
+/// Whether a heuristic is registered under `name`
+pub fn is_registered(name: &str) -> bool {
+    registry().read().expect("lock").contains_key(name)
+}
+
+/// Build the heuristic of a config item
+pub fn build_heuristic(item: &HeuristicItem) -> Result<Arc<dyn Heuristic>> {
+    let factory = registry()
+        .read()
+        .expect("lock")
+        .get(&item.heuristic)
+        .cloned()
+        .ok_or_else(|| anyhow!("unknown heuristic {}", item.heuristic))?;
+    factory(&item.params).with_context(|| format!("params of heuristic {}", item.heuristic))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use fnn::rpc::peer::PeerId;
+    use serde_json::json;
+
+    use super::is_registered;
+    use crate::{
+        config::HeuristicConfig,
+        graph::Graph,
+        heuristics::{get_node_scores, validate},
+        testing::{context, peer, register_fixed},
+    };
+
+    fn config(heuristic: serde_json::Value) -> HeuristicConfig {
+        serde_json::from_value(json!({ "heuristics": [heuristic] })).expect("config")
+    }
+
+    #[tokio::test]
+    async fn test_custom_heuristic() {
+        register_fixed();
+        assert!(is_registered("Fixed"));
+        let config = config(json!({
+            "heuristic": "Fixed",
+            "weight": 2.0,
+            "scores": [[1, 0.25], [2, 0.5]],
+        }));
+        validate(&config).expect("validate");
+
+        let graph = Arc::new(Graph::build(Vec::default(), Vec::default()));
+        let nodes: HashSet<PeerId> = (1..4).map(peer).collect();
+        let scores = get_node_scores(&config, graph, nodes, &context())
+            .await
+            .expect("scores");
+        let combined: Vec<f64> = (1..4).map(|i| scores[&peer(i)].score).collect();
+        assert_eq!(combined, vec![0.5, 1.0, 0.0]);
+        assert_eq!(scores[&peer(2)].details[0].heuristic, "Fixed");
+    }
+
+    #[test]
+    fn test_validate_names_and_params() {
+        register_fixed();
+        let unknown = config(json!({ "heuristic": "Unknown", "weight": 1.0 }));
+        assert!(validate(&unknown).is_err());
+        let custom = config(json!({ "heuristic": "Fixed", "weight": 1.0, "scores": [], "k": 1 }));
+        assert!(validate(&custom).is_err());
+        let builtin =
+            config(json!({ "heuristic": "Stability", "weight": 1.0, "prefer_old": true }));
+        assert!(validate(&builtin).is_err());
+        let builtin =
+            config(json!({ "heuristic": "Stability", "weight": 1.0, "prefer": "Newcomer" }));
+        validate(&builtin).expect("validate");
+    }
+}
@@ -5,13 +5,33 @@ use std::{
 
 use anyhow::Result;
 use fnn::{fiber::types::Pubkey, rpc::peer::PeerId};
+use serde::Deserialize;
 
-use crate::graph::Graph;
+use crate::{
+    graph::Graph,
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+};
 
 /// Determine the minimum size that channel count as positive
 const MIN_MEDIAN_CHAN_CAP_FRACTION: u128 = 4;
 
-pub async fn get_node_scores(
+/// Number of large channels of the node
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Richness {}
+
+impl Heuristic for Richness {
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        _context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        Box::pin(get_node_scores(graph, nodes))
+    }
+}
+
+async fn get_node_scores(
     graph: Arc<Graph>,
     nodes: HashSet<PeerId>,
 ) -> Result<HashMap<PeerId, f64>> {
@@ -0,0 +1,151 @@
+//! Score candidates by how long they and their channels have existed and how recently
+//! they re-announced
+//!
+//! Age of a node is the age of its oldest channel since node announcements only carry
+//! the latest timestamp.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use fnn::{fiber::types::Pubkey, rpc::peer::PeerId};
+use serde::Deserialize;
+
+use crate::{
+    config::AgePreference,
+    graph::Graph,
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+    utils::unix_timestamp,
+};
+
+/// Freshness of an announcement halves every day
+const ANNOUNCEMENT_HALF_LIFE_MS: f64 = 24.0 * 3600.0 * 1000.0;
+/// Weight of the announcement freshness, the rest goes to age
+const FRESHNESS_WEIGHT: f64 = 0.3;
+
+/// Age of the node and its channels and freshness of its announcement
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stability {
+    pub prefer: AgePreference,
+}
+
+impl Heuristic for Stability {
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        _context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        Box::pin(get_node_scores(graph, nodes, self.prefer))
+    }
+}
+
+async fn get_node_scores(
+    graph: Arc<Graph>,
+    nodes: HashSet<PeerId>,
+    prefer: AgePreference,
+) -> Result<HashMap<PeerId, f64>> {
+    // timestamps are in milliseconds
+    let now = unix_timestamp() * 1000;
+
+    // ages of channels of each node
+    let mut channel_ages: HashMap<PeerId, Vec<u64>> = HashMap::default();
+    let mut add_age = |n: Pubkey, age: u64| {
+        let peer = PeerId::from_public_key(&n.into());
+        if nodes.contains(&peer) {
+            channel_ages.entry(peer).or_default().push(age);
+        }
+    };
+    for c in graph.channels() {
+        let age = now.saturating_sub(c.created_timestamp);
+        add_age(c.node1, age);
+        add_age(c.node2, age);
+    }
+
+    // node age is the oldest channel, channel age is the median
+    let mut ages: HashMap<PeerId, (u64, u64)> = HashMap::default();
+    for (peer, mut channel_ages) in channel_ages {
+        channel_ages.sort_unstable();
+        let oldest = channel_ages.last().cloned().unwrap_or_default();
+        let median = channel_ages[channel_ages.len() / 2];
+        ages.insert(peer, (oldest, median));
+    }
+    let max_oldest = ages.values().map(|(o, _)| *o).max().unwrap_or_default();
+    let max_median = ages.values().map(|(_, m)| *m).max().unwrap_or_default();
+    let ratio = |v: u64, max: u64| if max > 0 { v as f64 / max as f64 } else { 0.0 };
+
+    let mut announced: HashMap<PeerId, u64> = HashMap::default();
+    for node in graph.nodes() {
+        let peer = PeerId::from_public_key(&node.node_id.into());
+        if nodes.contains(&peer) {
+            announced.insert(peer, node.timestamp);
+        }
+    }
+
+    let scores = nodes
+        .into_iter()
+        .map(|peer| {
+            let (oldest, median) = ages.get(&peer).cloned().unwrap_or_default();
+            let age = (ratio(oldest, max_oldest) + ratio(median, max_median)) / 2.0;
+            let elapsed = announced
+                .get(&peer)
+                .map(|t| now.saturating_sub(*t) as f64)
+                .unwrap_or(f64::INFINITY);
+            let freshness = 0.5f64.powf(elapsed / ANNOUNCEMENT_HALF_LIFE_MS);
+            // newcomers still need to be alive
+            let age = match prefer {
+                AgePreference::Established => age,
+                AgePreference::Newcomer => 1.0 - age,
+            };
+            let s = (1.0 - FRESHNESS_WEIGHT) * age + FRESHNESS_WEIGHT * freshness;
+            (peer, s)
+        })
+        .collect();
+    Ok(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::Stability;
+    use crate::{
+        config::AgePreference,
+        testing::{channel, context, graph, node, peer},
+        traits::Heuristic,
+        utils::unix_timestamp,
+    };
+
+    #[tokio::test]
+    async fn test_age_ordering() {
+        // channels of peers 1 ~ 3 were created 30, 1 and 10 days ago, all peers just announced
+        let now = unix_timestamp() * 1000;
+        let channels = [30, 1, 10]
+            .into_iter()
+            .enumerate()
+            .map(|(i, days)| {
+                let mut c = channel(i, 0, i + 1);
+                c["created_timestamp"] = json!(format!("{:#x}", now - days * 24 * 3600 * 1000));
+                c
+            })
+            .collect();
+        let graph = Arc::new(graph((0..4).map(node).collect(), channels));
+
+        for (prefer, expected) in [
+            (AgePreference::Established, [1, 3, 2]),
+            (AgePreference::Newcomer, [2, 3, 1]),
+        ] {
+            let scores = Stability { prefer }
+                .get_node_scores(Arc::clone(&graph), (1..4).map(peer).collect(), &context())
+                .await
+                .expect("scores");
+            let [first, second, third] = expected.map(|i| scores[&peer(i)]);
+            assert!(first > second && second > third, "{prefer:?} {scores:?}");
+        }
+    }
+}
@@ -0,0 +1,16 @@
+pub mod agent;
+pub mod allocate;
+pub mod budget;
+pub mod config;
+pub mod graph;
+pub mod graph_source;
+pub mod heuristics;
+pub mod plan;
+pub mod rpc;
+pub mod select;
+pub mod simulate;
+pub mod store;
+#[cfg(test)]
+pub mod testing;
+pub mod traits;
+pub mod utils;
@@ -1,19 +1,28 @@
-mod agent;
-mod config;
-mod graph;
-mod graph_source;
-mod heuristics;
-mod rpc;
-mod traits;
-mod utils;
-
-use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Result};
 use ckb_sdk::CkbRpcAsyncClient;
-use clap::Parser;
-use config::Config;
-use graph_source::rpc::RPCGraphSource;
-use rpc::client::RPCClient;
-use std::fs;
+use clap::{Parser, Subcommand};
+use fiber_autopilot::{
+    agent,
+    budget::BudgetCoordinator,
+    config::{AgentConfig, Config, TokenType},
+    graph_source::{
+        rpc::RPCGraphSource,
+        snapshot::{self, SnapshotGraphSource},
+    },
+    plan::{AgentPlan, Plan},
+    rpc::client::RPCClient,
+    simulate::{self, parse_strategy, Strategy},
+    store::Store,
+    traits::GraphSource,
+    utils::unix_timestamp,
+};
 use tokio::task::JoinSet;
 use tracing::{error, info};
 
@@ -32,10 +41,74 @@ struct Args {
         default_value = "fiber-autopilot.toml"
     )]
     config: String,
+    /// Serve queries from a snapshot directory instead of the fiber and ckb RPC,
+    /// connect and open requests are recorded instead of executed
+    #[arg(long, value_name = "DIR", global = true)]
+    snapshot: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run agents to open channels continuously, this is the default command
+    Run,
+    /// Recommend channels to open without opening them
+    Recommend {
+        /// Write the plan to a JSON file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Open channels of a plan written by `recommend`
+    Apply {
+        /// Plan file
+        plan: PathBuf,
+    },
+    /// Backtest heuristic strategies, usually on a snapshot with `--snapshot`
+    Simulate {
+        /// Agent rounds to run
+        #[arg(long, default_value_t = 10)]
+        rounds: usize,
+        /// Count nodes reachable within hops
+        #[arg(long, default_value_t = 3)]
+        hops: usize,
+        /// Heuristic weights like `Centrality=0.8,Richness=0.1,Random=0.1` applied on the
+        /// first agent, can be repeated. Every configured agent is a strategy if not set
+        #[arg(long = "strategy", value_name = "WEIGHTS")]
+        strategies: Vec<String>,
+        /// Seed of rounds of strategies without `selection.seed`
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Dump graph, local channels and balances into a snapshot directory
+    Snapshot {
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+    },
 }
 
 fn init_log() {
-    tracing_subscriber::fmt().init();
+    // keep stdout for command output
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Names of agents in the config, the keys of their states in the store
+fn agent_names(agents: &[AgentConfig]) -> Result<Vec<String>> {
+    let mut names = HashSet::new();
+    agents
+        .iter()
+        .map(|config| {
+            let name = config.name().to_string();
+            if !names.insert(name.clone()) {
+                bail!(
+                    "Duplicate agent name {name}, set distinct `name` of agents of the same token"
+                );
+            }
+            Ok(name)
+        })
+        .collect()
 }
 
 #[tokio::main]
@@ -47,24 +120,99 @@ async fn main() -> Result<()> {
 
     let data = fs::read_to_string(&args.config)?;
     let config: Config = toml::from_str(&data)?;
-    let source = {
-        let fiber_client = RPCClient::new(&config.fiber.url);
-        let ckb_client = CkbRpcAsyncClient::new(&config.ckb.url);
-        RPCGraphSource::new(fiber_client, ckb_client)
-    };
+    let command = args.command.unwrap_or(Command::Run);
+    let names = agent_names(&config.agents)?;
+
+    match args.snapshot {
+        Some(dir) => {
+            info!("Replay snapshot {}", dir.display());
+            let source = SnapshotGraphSource::load(&dir)?;
+            // never touch the state of the live node
+            let store = Store::in_memory();
+            execute(command, config, source.clone(), store).await?;
+            for action in source.actions() {
+                info!("Recorded {action}");
+            }
+            Ok(())
+        }
+        None => {
+            config.fiber.graph_query.validate()?;
+            let source = {
+                let fiber_client = RPCClient::new(&config.fiber.url);
+                let ckb_client = CkbRpcAsyncClient::new(&config.ckb.url);
+                RPCGraphSource::new(fiber_client, ckb_client, config.fiber.graph_query)
+            };
+            let store = Store::open(&config.data_dir)?;
+            store.rename_positional(&names)?;
+            execute(command, config, source, store).await
+        }
+    }
+}
 
+async fn execute<GS: GraphSource + Send + Sync + Clone + Debug + 'static>(
+    command: Command,
+    config: Config,
+    source: GS,
+    store: Store,
+) -> Result<()> {
+    match command {
+        Command::Run => run(config, source, store).await,
+        Command::Recommend { output } => recommend(config, source, store, output).await,
+        Command::Apply { plan } => apply(config, source, store, plan).await,
+        Command::Simulate {
+            rounds,
+            hops,
+            strategies,
+            seed,
+        } => {
+            let strategies: Vec<Strategy> = if strategies.is_empty() {
+                config
+                    .agents
+                    .into_iter()
+                    .map(|config| Strategy {
+                        name: config.name().to_string(),
+                        config,
+                    })
+                    .collect()
+            } else {
+                let base = config
+                    .agents
+                    .first()
+                    .ok_or_else(|| anyhow!("No agent in the config"))?;
+                strategies
+                    .iter()
+                    .map(|s| parse_strategy(s, base))
+                    .collect::<Result<_>>()?
+            };
+            simulate::simulate(source, strategies, rounds, hops, seed).await
+        }
+        Command::Snapshot { output } => {
+            let tokens: Vec<TokenType> = config.agents.into_iter().map(|c| c.token).collect();
+            snapshot::dump(&source, &tokens, &output).await
+        }
+    }
+}
+
+async fn run<GS: GraphSource + Send + Sync + Clone + Debug + 'static>(
+    config: Config,
+    source: GS,
+    store: Store,
+) -> Result<()> {
+    // agents share the wallet of the node
+    let budget = BudgetCoordinator::default();
     let handle: JoinSet<_> = config
         .agents
         .into_iter()
-        .enumerate()
-        .map(|(index, config)| {
-            let name = format!("agent-{index}");
+        .map(|config| {
+            let name = config.name().to_string();
             let source = source.clone();
+            let store = store.clone();
+            let budget = budget.clone();
             tokio::spawn(async {
                 let token = config.token.name().to_string();
-                match agent::Agent::setup(name, config, source).await {
+                match agent::Agent::setup(name, config, source, store).await {
                     Ok(agent) => {
-                        agent.run().await;
+                        agent.with_budget(budget).run().await;
                     }
                     Err(err) => {
                         error!("Failed to setup agent {token} error {err:?}");
@@ -84,3 +232,64 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+async fn recommend<GS: GraphSource + Send + Sync + Clone + Debug + 'static>(
+    config: Config,
+    source: GS,
+    store: Store,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let mut plan = Plan {
+        created_at: unix_timestamp(),
+        agents: Vec::default(),
+    };
+    let budget = BudgetCoordinator::default();
+    for config in config.agents {
+        let name = config.name().to_string();
+        let mut agent = agent::Agent::setup(name.clone(), config, source.clone(), store.clone())
+            .await?
+            .with_budget(budget.clone());
+        match agent.recommend().await {
+            Ok(channels) => {
+                info!("Agent {name} recommends {} channels", channels.len());
+                plan.agents.push(AgentPlan {
+                    agent: name,
+                    channels,
+                });
+            }
+            Err(err) => {
+                error!("Failed to recommend channels {name} error {err:?}");
+            }
+        }
+    }
+    plan.write(output.as_deref())
+}
+
+async fn apply<GS: GraphSource + Send + Sync + Clone + Debug + 'static>(
+    config: Config,
+    source: GS,
+    store: Store,
+    plan: PathBuf,
+) -> Result<()> {
+    let plan = Plan::load(&plan)?;
+    let mut configs: HashMap<String, AgentConfig> = config
+        .agents
+        .into_iter()
+        .map(|config| (config.name().to_string(), config))
+        .collect();
+
+    let budget = BudgetCoordinator::default();
+    for AgentPlan { agent, channels } in plan.agents {
+        let Some(config) = configs.remove(&agent) else {
+            error!("Skipping unknown agent {agent} in the plan");
+            continue;
+        };
+        let mut agent = agent::Agent::setup(agent, config, source.clone(), store.clone())
+            .await?
+            .with_budget(budget.clone());
+        if let Err(err) = agent.apply(channels).await {
+            error!("Failed to apply plan {} error {err:?}", agent.name);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+//! Channel opening plan written by `recommend` and executed by `apply`
+
+use std::{fs, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::agent::OpenChannelCmd;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Plan {
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    pub agents: Vec<AgentPlan>,
+}
+
+/// Channels recommended by an agent
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AgentPlan {
+    pub agent: String,
+    pub channels: Vec<OpenChannelCmd>,
+}
+
+impl Plan {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data =
+            fs::read_to_string(path).with_context(|| format!("read plan {}", path.display()))?;
+        serde_json::from_str(&data).with_context(|| format!("parse plan {}", path.display()))
+    }
+
+    /// Write plan to the file, or stdout if output is not set
+    pub fn write(&self, output: Option<&Path>) -> Result<()> {
+        let data = serde_json::to_string_pretty(self)?;
+        match output {
+            Some(path) => {
+                fs::write(path, data).with_context(|| format!("write plan {}", path.display()))
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                writeln!(stdout, "{data}")?;
+                Ok(())
+            }
+        }
+    }
+}
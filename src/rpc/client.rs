@@ -84,6 +84,10 @@ impl RPCClient {
         self.call("shutdown_channel", rpc_params!(params)).await
     }
 
+    pub async fn abandon_channel(&self, params: AbandonChannelParams) -> Result<()> {
+        self.call("abandon_channel", rpc_params!(params)).await
+    }
+
     pub async fn update_channel(&self, params: UpdateChannelParams) -> Result<()> {
         self.call("update_channel", rpc_params!(params)).await
     }
@@ -0,0 +1,122 @@
+//! Pick candidates by their scores
+
+use anyhow::{bail, Result};
+use rand::Rng;
+
+use crate::{config::SelectionStrategy, utils::choice_n};
+
+impl SelectionStrategy {
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::TopK | Self::Weighted => {}
+            Self::Softmax { temperature } => {
+                if !temperature.is_finite() || temperature <= 0.0 {
+                    bail!("temperature of Softmax must be positive, got {temperature}");
+                }
+            }
+            Self::EpsilonGreedy { epsilon } => {
+                if !(0.0..=1.0).contains(&epsilon) {
+                    bail!("epsilon of EpsilonGreedy must be within 0.0 ~ 1.0, got {epsilon}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick up to `n` items in the order of picking, picks only depend on the order of
+    /// `items` and the state of `rng`
+    pub fn select<T: Clone, R: Rng + ?Sized>(
+        &self,
+        items: Vec<(T, f64)>,
+        n: usize,
+        rng: &mut R,
+    ) -> Vec<(T, f64)> {
+        let mut items: Vec<(T, f64)> = items
+            .into_iter()
+            .filter(|(_, s)| s.is_finite() && *s > 0.0)
+            .collect();
+        match *self {
+            Self::TopK => {
+                items.sort_by(|a, b| b.1.total_cmp(&a.1));
+                items.truncate(n);
+                items
+            }
+            Self::Weighted => choice_n(items, n, rng),
+            Self::Softmax { temperature } => {
+                let max = items.iter().map(|i| i.1).fold(f64::NEG_INFINITY, f64::max);
+                // keep underflowed weights positive so they are picked after the others
+                let weights = items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, s))| (i, ((s - max) / temperature).exp().max(f64::MIN_POSITIVE)))
+                    .collect();
+                choice_n(weights, n, rng)
+                    .into_iter()
+                    .map(|(i, _)| items[i].clone())
+                    .collect()
+            }
+            Self::EpsilonGreedy { epsilon } => {
+                items.sort_by(|a, b| b.1.total_cmp(&a.1));
+                let mut picks = Vec::with_capacity(n.min(items.len()));
+                while picks.len() < n && !items.is_empty() {
+                    let i = if rng.random_bool(epsilon) {
+                        rng.random_range(0..items.len())
+                    } else {
+                        0
+                    };
+                    picks.push(items.remove(i));
+                }
+                picks
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::config::SelectionStrategy;
+
+    #[test]
+    fn test_selection_strategies() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let items = vec![(1, 0.2), (2, 0.0), (3, 0.9), (4, 0.5)];
+        let pick = |strategy: SelectionStrategy, n: usize, rng: &mut StdRng| -> Vec<usize> {
+            strategy
+                .select(items.clone(), n, rng)
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect()
+        };
+
+        assert_eq!(pick(SelectionStrategy::TopK, 2, &mut rng), vec![3, 4]);
+        assert_eq!(
+            pick(
+                SelectionStrategy::EpsilonGreedy { epsilon: 0.0 },
+                4,
+                &mut rng
+            ),
+            vec![3, 4, 1]
+        );
+        // zero scores are never picked
+        let mut weighted = pick(SelectionStrategy::Weighted, 4, &mut rng);
+        weighted.sort_unstable();
+        assert_eq!(weighted, vec![1, 3, 4]);
+        let mut softmax = pick(
+            SelectionStrategy::Softmax { temperature: 0.01 },
+            3,
+            &mut rng,
+        );
+        softmax.sort_unstable();
+        assert_eq!(softmax, vec![1, 3, 4]);
+        // all zero scores pick nothing instead of panicking
+        let zeros = vec![(1, 0.0), (2, 0.0), (3, 0.0)];
+        assert!(SelectionStrategy::Weighted
+            .select(zeros.clone(), 1, &mut rng)
+            .is_empty());
+        assert!(SelectionStrategy::Softmax { temperature: 1.0 }
+            .select(zeros, 1, &mut rng)
+            .is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+//! Backtest heuristic strategies on a graph, usually a snapshot
+//!
+//! Each strategy runs agent rounds from the same graph, chosen opens are applied
+//! to the graph as new channels of the autopilot node, then metrics of the autopilot
+//! node are measured on the grown graph. Rounds of strategies without `selection.seed`
+//! are seeded from `seed` so runs are reproducible and comparable.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ckb_jsonrpc_types::Script;
+use fnn::{
+    fiber::types::Pubkey,
+    rpc::{
+        graph::{ChannelInfo, NodeInfo},
+        peer::PeerId,
+    },
+};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{
+    agent::Agent,
+    config::{AgentConfig, HeuristicItem},
+    graph::Graph,
+    heuristics::{is_registered, BetweennessCentrality},
+    store::Store,
+    traits::GraphSource,
+    utils::{conv, unix_timestamp},
+};
+
+/// A heuristic config to backtest
+pub struct Strategy {
+    pub name: String,
+    pub config: AgentConfig,
+}
+
+/// Metrics of the autopilot node after a round
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Rank of betweenness centrality, 1 is the most central node
+    pub rank: usize,
+    /// Average hop distance to reachable nodes
+    pub avg_hops: f64,
+    /// Nodes reachable within k hops
+    pub reachable: usize,
+    /// Funds of simulated channels
+    pub capital: u128,
+}
+
+/// Parse a strategy like `Centrality=0.8,Richness=0.1,Random=0.1`
+pub fn parse_strategy(s: &str, base: &AgentConfig) -> Result<Strategy> {
+    let mut heuristics = Vec::default();
+    for item in s.split(',') {
+        let (name, weight) = item
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expect heuristic=weight, got {item}"))?;
+        let name = name.trim();
+        if !is_registered(name) {
+            bail!("unknown heuristic {name}");
+        }
+        let weight: f32 = weight.trim().parse()?;
+        // keep params and normalization of the heuristic in the base config
+        let mut item = base
+            .heuristics
+            .heuristics
+            .iter()
+            .find(|h| h.heuristic == name)
+            .cloned()
+            .unwrap_or_else(|| HeuristicItem {
+                heuristic: name.to_string(),
+                weight,
+                normalize: Default::default(),
+                transforms: Default::default(),
+                params: Default::default(),
+            });
+        item.weight = weight;
+        heuristics.push(item);
+    }
+    let mut config = base.clone();
+    config.heuristics.heuristics = heuristics;
+    Ok(Strategy {
+        name: s.to_string(),
+        config,
+    })
+}
+
+/// Run strategies on the graph of the source and print metrics side by side,
+/// the source is only queried, channels are never opened
+pub async fn simulate<GS: GraphSource + Send + Clone + Debug + 'static>(
+    source: GS,
+    strategies: Vec<Strategy>,
+    rounds: usize,
+    hops: usize,
+    seed: u64,
+) -> Result<()> {
+    if strategies.is_empty() {
+        bail!("No strategy to simulate");
+    }
+    let mut results = Vec::with_capacity(strategies.len());
+    for strategy in &strategies {
+        info!("Simulate strategy {}", strategy.name);
+        results.push(run_strategy(source.clone(), strategy, rounds, hops, seed).await?);
+    }
+    print_table(&strategies, &results, hops);
+    Ok(())
+}
+
+async fn run_strategy<GS: GraphSource + Send + Clone + Debug + 'static>(
+    source: GS,
+    strategy: &Strategy,
+    rounds: usize,
+    hops: usize,
+    seed: u64,
+) -> Result<Vec<Metrics>> {
+    let node_info = source.node_info().await?;
+    let self_id = node_info.node_id;
+    let funding_lock: Script = conv!(&node_info.default_funding_lock_script);
+    let chain_hash = serde_json::to_value(node_info.chain_hash)?;
+
+    // fiber types are kept as JSON, so simulated channels can be appended
+    let mut nodes: Vec<Value> = serde_json::to_value(source.graph_nodes().await?)?
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let mut channels: Vec<Value> = serde_json::to_value(source.graph_channels().await?)?
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    // the agent scores and metrics are measured on the token view
+    let build_graph = |nodes: &[Value], channels: &[Value]| -> Result<Graph> {
+        let nodes: Vec<NodeInfo> = serde_json::from_value(Value::Array(nodes.to_vec()))?;
+        let channels: Vec<ChannelInfo> = serde_json::from_value(Value::Array(channels.to_vec()))?;
+        let config = &strategy.config;
+        Ok(Graph::build_view(
+            nodes,
+            channels,
+            &config.token,
+            config.graph_view,
+        ))
+    };
+
+    // the autopilot node may not be announced yet
+    let graph = build_graph(&nodes, &channels)?;
+    if graph.node_index(&self_id).is_none() {
+        nodes.push(json!({
+            "node_name": "autopilot",
+            "addresses": [],
+            "node_id": self_id,
+            "timestamp": "0x0",
+            "chain_hash": chain_hash,
+            "auto_accept_min_ckb_funding_amount": "0x0",
+            "udt_cfg_infos": [],
+        }));
+    }
+
+    let config = strategy.config.clone();
+    let token = config.token.clone();
+    let mut local_peers: HashSet<PeerId> = source
+        .local_channels()
+        .await?
+        .into_iter()
+        .filter(|c| token.is_token(c.funding_udt_type_script.as_ref().map(|s| conv!(s))))
+        .map(|c| c.peer_id)
+        .collect();
+    let fixed_seed = config.selection.seed;
+    let mut agent = Agent::new(
+        strategy.name.clone(),
+        self_id,
+        funding_lock,
+        config,
+        source,
+        Store::in_memory(),
+    );
+    // reserved funds are kept out like the live agent
+    let mut available_funds = agent.query_available_funds().await?;
+    let open_fee = agent.config.reserve.open_fee;
+
+    let mut capital = 0;
+    let mut metrics = Vec::with_capacity(rounds + 1);
+    let graph = Arc::new(build_graph(&nodes, &channels)?);
+    metrics.push(measure(&graph, &self_id, hops, capital).await?);
+
+    for round in 1..=rounds {
+        let graph = Arc::new(build_graph(&nodes, &channels)?);
+        let peer_keys: HashMap<PeerId, Pubkey> = graph
+            .nodes()
+            .iter()
+            .map(|n| (PeerId::from_public_key(&n.node_id.into()), n.node_id))
+            .collect();
+        let num = agent
+            .config
+            .max_chan_num
+            .saturating_sub(local_peers.len())
+            .min(20);
+        agent.config.selection.seed =
+            Some(fixed_seed.unwrap_or_else(|| seed.wrapping_add(round as u64)));
+        let cmds = match agent
+            .select_channels(available_funds, num, graph, local_peers.clone())
+            .await
+        {
+            Ok(cmds) => cmds,
+            Err(err) => {
+                warn!("Stop opening channels at round {round} {err:?}");
+                Vec::default()
+            }
+        };
+
+        for cmd in cmds {
+            available_funds = available_funds.saturating_sub(cmd.funds + open_fee);
+            capital += cmd.funds;
+            local_peers.insert(cmd.peer.clone());
+            // external nodes out of the graph only count as capital
+            let Some(node_id) = peer_keys.get(&cmd.peer) else {
+                continue;
+            };
+            let index = channels.len();
+            channels.push(json!({
+                "channel_outpoint": format!("0x{index:064x}{:08x}", u32::MAX),
+                "node1": self_id,
+                "node2": node_id,
+                "created_timestamp": format!("{:#x}", unix_timestamp() * 1000),
+                "capacity": format!("{:#x}", cmd.funds),
+                "chain_hash": chain_hash,
+                "udt_type_script": cmd.token.script(),
+            }));
+        }
+
+        let graph = Arc::new(build_graph(&nodes, &channels)?);
+        metrics.push(measure(&graph, &self_id, hops, capital).await?);
+    }
+    Ok(metrics)
+}
+
+async fn measure(
+    graph: &Arc<Graph>,
+    self_id: &Pubkey,
+    hops: usize,
+    capital: u128,
+) -> Result<Metrics> {
+    let s = graph
+        .node_index(self_id)
+        .ok_or_else(|| anyhow!("self node is not in the graph"))?;
+
+    let self_peer = PeerId::from_public_key(&(*self_id).into());
+    let centrality = BetweennessCentrality::build(Arc::clone(graph))
+        .await?
+        .get(false);
+    let self_centrality = centrality.get(&self_peer).cloned().unwrap_or_default();
+    let rank = 1 + centrality
+        .values()
+        .filter(|c| **c > self_centrality)
+        .count();
+
+    let dist = graph.hop_distances(s);
+    let reachable_hops: Vec<usize> = dist
+        .iter()
+        .enumerate()
+        .filter_map(|(i, d)| if i == s { None } else { *d })
+        .collect();
+    let avg_hops = if reachable_hops.is_empty() {
+        0.0
+    } else {
+        reachable_hops.iter().sum::<usize>() as f64 / reachable_hops.len() as f64
+    };
+    let reachable = reachable_hops.iter().filter(|d| **d <= hops).count();
+
+    Ok(Metrics {
+        rank,
+        avg_hops,
+        reachable,
+        capital,
+    })
+}
+
+fn print_table(strategies: &[Strategy], results: &[Vec<Metrics>], hops: usize) {
+    const COL: usize = 44;
+    let mut header = format!("{:<6}", "round");
+    let mut columns = format!("{:<6}", "");
+    for s in strategies {
+        header.push_str(&format!("| {:<width$}", s.name, width = COL - 2));
+        columns.push_str(&format!(
+            "| {:<6}{:<10}{:<10}{:<16}",
+            "rank",
+            "avg_hops",
+            format!("<={hops}hop"),
+            "capital"
+        ));
+    }
+    println!("{header}");
+    println!("{columns}");
+    let rounds = results.iter().map(|r| r.len()).max().unwrap_or_default();
+    for round in 0..rounds {
+        let mut line = format!("{round:<6}");
+        for r in results {
+            match r.get(round) {
+                Some(m) => line.push_str(&format!(
+                    "| {:<6}{:<10.3}{:<10}{:<16}",
+                    m.rank, m.avg_hops, m.reachable, m.capital
+                )),
+                None => line.push_str(&format!("| {:<width$}", "-", width = COL - 2)),
+            }
+        }
+        println!("{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_strategy, run_strategy};
+    use crate::{
+        config::AgentConfig,
+        graph_source::memory::{MemoryGraphSource, Method},
+        testing::{channel, node, node_info},
+    };
+
+    const CONFIG: &str = r#"
+token.type = "Ckb"
+external_nodes = []
+interval = 1
+max_chan_num = 100
+max_pending = 20
+min_chan_funds = "0x64"
+max_chan_funds = "0x3e8"
+selection = { type = "TopK" }
+reserve = { open_fee = "0x64" }
+[[heuristics]]
+heuristic = "Random"
+weight = 1.0
+"#;
+
+    #[test]
+    fn test_parse_strategy() {
+        let base: AgentConfig = toml::from_str(CONFIG).expect("config");
+        let strategy = parse_strategy("Centrality=0.8, Random=0.2", &base).expect("strategy");
+        let weights: Vec<(&str, f32)> = strategy
+            .config
+            .heuristics
+            .heuristics
+            .iter()
+            .map(|h| (h.heuristic.as_str(), h.weight))
+            .collect();
+        assert_eq!(weights, vec![("Centrality", 0.8), ("Random", 0.2)]);
+        assert!(parse_strategy("Unknown=1", &base).is_err());
+        assert!(parse_strategy("Centrality", &base).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_simulate_rounds() {
+        // 3 is the hub of 1, 2 and 4, 4 bridges 5, the autopilot node 0 is isolated
+        let source = MemoryGraphSource::default();
+        source.set(Method::NodeInfo, node_info());
+        source.set_graph(
+            (0..6).map(node).collect(),
+            vec![
+                channel(0, 3, 1),
+                channel(1, 3, 2),
+                channel(2, 3, 4),
+                channel(3, 4, 5),
+            ],
+        );
+        source.set_balance(2150);
+        let base: AgentConfig = toml::from_str(CONFIG).expect("config");
+        let strategy = parse_strategy("Centrality=1", &base).expect("strategy");
+
+        let metrics = run_strategy(source, &strategy, 2, 3, 0)
+            .await
+            .expect("simulate");
+        assert_eq!(metrics.len(), 3);
+        assert_eq!(metrics[0].reachable, 0);
+        assert_eq!(metrics[0].capital, 0);
+        // opens to 3 and 4 pay the open fee, the rest of the funds is not enough for a third
+        assert_eq!(metrics[1].capital, 1950);
+        assert_eq!(metrics[1].reachable, 5);
+        assert!((metrics[1].avg_hops - 1.6).abs() < 1e-9);
+        assert_eq!(metrics[2].capital, 1950);
+    }
+}
@@ -0,0 +1,345 @@
+//! Persistent agent state
+//!
+//! All agents share a single JSON file under `data_dir`, each agent keeps its state under its name.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fnn::{
+    fiber::{serde_utils::U128Hex, types::Hash256},
+    rpc::peer::PeerId,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tracing::info;
+
+use crate::config::TokenType;
+
+/// Version of the store format, bump it and add a migration on incompatible changes
+pub const STORE_VERSION: u32 = 2;
+const STORE_FILE: &str = "autopilot-state.json";
+/// Max finished attempts kept for each agent
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct StoreData {
+    version: u32,
+    agents: HashMap<String, AgentState>,
+    /// Agents are keyed by their position as in version 1
+    #[serde(skip)]
+    positional: bool,
+}
+
+impl Default for StoreData {
+    fn default() -> Self {
+        Self {
+            version: STORE_VERSION,
+            agents: Default::default(),
+            positional: false,
+        }
+    }
+}
+
+/// A channel opening whose funding transaction is not confirmed yet
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingChannel {
+    /// Unix timestamp in seconds when the open was started
+    pub opened_at: u64,
+    /// Set once the node accepted the open channel request
+    pub temporary_channel_id: Option<Hash256>,
+    #[serde_as(as = "U128Hex")]
+    pub funds: u128,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum AttemptOutcome {
+    Opened { channel_id: Hash256 },
+    Failed { reason: String },
+    Expired,
+}
+
+/// A finished channel opening
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttemptRecord {
+    #[serde_as(as = "DisplayFromStr")]
+    pub peer: PeerId,
+    #[serde_as(as = "U128Hex")]
+    pub funds: u128,
+    pub temporary_channel_id: Option<Hash256>,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+    /// Unix timestamp in seconds
+    pub finished_at: u64,
+    pub outcome: AttemptOutcome,
+}
+
+/// Everything an agent remembers across restarts
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AgentState {
+    /// Token of the agent owning the state, `None` if it was stored by version 1
+    #[serde(default)]
+    pub token: Option<TokenType>,
+    #[serde_as(as = "HashMap<DisplayFromStr, _>")]
+    pub pending: HashMap<PeerId, PendingChannel>,
+    /// Finished attempts, the oldest comes first
+    pub history: Vec<AttemptRecord>,
+    /// Channels opened by the agent until they are closed, kept apart from the truncated history
+    #[serde(default)]
+    pub opened: HashSet<Hash256>,
+    /// Unix timestamp in seconds when a local channel was first found prunable
+    #[serde(default)]
+    pub prune_flagged: HashMap<Hash256, u64>,
+}
+
+impl AgentState {
+    /// Funds committed to pending channels
+    pub fn pending_funds(&self) -> u128 {
+        self.pending.values().map(|p| p.funds).sum()
+    }
+
+    /// Move a pending channel to history
+    pub fn finish(
+        &mut self,
+        peer: &PeerId,
+        outcome: AttemptOutcome,
+        finished_at: u64,
+    ) -> Option<PendingChannel> {
+        let pending = self.pending.remove(peer)?;
+        if let AttemptOutcome::Opened { channel_id } = outcome {
+            self.opened.insert(channel_id);
+        }
+        self.history.push(AttemptRecord {
+            peer: peer.clone(),
+            funds: pending.funds,
+            temporary_channel_id: pending.temporary_channel_id,
+            started_at: pending.opened_at,
+            finished_at,
+            outcome,
+        });
+        if self.history.len() > MAX_HISTORY {
+            let n = self.history.len() - MAX_HISTORY;
+            self.history.drain(..n);
+        }
+        Some(pending)
+    }
+
+    /// Peers whose last attempt failed or expired after `since`
+    pub fn failed_peers(&self, since: u64) -> impl Iterator<Item = &PeerId> {
+        self.history.iter().filter_map(move |r| match r.outcome {
+            AttemptOutcome::Failed { .. } | AttemptOutcome::Expired if r.finished_at >= since => {
+                Some(&r.peer)
+            }
+            _ => None,
+        })
+    }
+}
+
+/// State store shared by agents
+#[derive(Clone)]
+pub struct Store {
+    /// Keep state in memory only if `None`
+    path: Option<PathBuf>,
+    data: Arc<Mutex<StoreData>>,
+}
+
+impl Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store").field("path", &self.path).finish()
+    }
+}
+
+impl Store {
+    /// Open the store under `data_dir`, create an empty one if not exists
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(data_dir)
+            .with_context(|| format!("create data dir {}", data_dir.display()))?;
+        let path = data_dir.join(STORE_FILE);
+        let data = if path.exists() {
+            let raw = fs::read_to_string(&path)
+                .with_context(|| format!("read store {}", path.display()))?;
+            let value: serde_json::Value = serde_json::from_str(&raw)
+                .with_context(|| format!("parse store {}", path.display()))?;
+            migrate(value)?
+        } else {
+            StoreData::default()
+        };
+        info!(
+            "Open state store {} version {} agents {}",
+            path.display(),
+            data.version,
+            data.agents.len()
+        );
+        Ok(Self {
+            path: Some(path),
+            data: Arc::new(Mutex::new(data)),
+        })
+    }
+
+    /// A store never touches the disk, used to replay snapshots
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            data: Default::default(),
+        }
+    }
+
+    /// Load state of an agent
+    pub fn load(&self, agent: &str) -> AgentState {
+        let data = self.data.lock().expect("lock");
+        data.agents.get(agent).cloned().unwrap_or_default()
+    }
+
+    /// Save state of an agent and flush to disk
+    pub fn save(&self, agent: &str, state: &AgentState) -> Result<()> {
+        let mut data = self.data.lock().expect("lock");
+        data.agents.insert(agent.to_string(), state.clone());
+        self.flush(&data)
+    }
+
+    /// Rename states keyed by the position of the agent in a version 1 store to `names`
+    /// of agents in the same order, nothing is renamed for later versions
+    pub fn rename_positional(&self, names: &[String]) -> Result<()> {
+        let mut data = self.data.lock().expect("lock");
+        if !data.positional {
+            return Ok(());
+        }
+        let mut agents = std::mem::take(&mut data.agents);
+        for (index, name) in names.iter().enumerate() {
+            if let Some(state) = agents.remove(&format!("agent-{index}")) {
+                info!("Rename state of agent-{index} to {name}");
+                data.agents.insert(name.clone(), state);
+            }
+        }
+        // states of removed agents are kept under their old keys
+        for (key, state) in agents {
+            data.agents.entry(key).or_insert(state);
+        }
+        data.positional = false;
+        self.flush(&data)
+    }
+
+    fn flush(&self, data: &StoreData) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        let raw = serde_json::to_string_pretty(data)?;
+        // write to a temporary file then rename, so a crash never leaves a partial store
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, raw).with_context(|| format!("write store {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("rename store {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Upgrade a stored value to the current version
+fn migrate(value: serde_json::Value) -> Result<StoreData> {
+    let version = value
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| anyhow!("store has no version"))?;
+    match version {
+        // version 1 states have no token and are keyed by the position of the agent,
+        // they are renamed by `Store::rename_positional` once agent names are known
+        1 => {
+            let mut data: StoreData = serde_json::from_value(value)?;
+            data.version = STORE_VERSION;
+            data.positional = true;
+            // version 1 only remembers opened channels in the history
+            for state in data.agents.values_mut() {
+                let opened = state.history.iter().filter_map(|r| match r.outcome {
+                    AttemptOutcome::Opened { channel_id } => Some(channel_id),
+                    _ => None,
+                });
+                state.opened = opened.collect();
+            }
+            Ok(data)
+        }
+        STORE_VERSION => Ok(serde_json::from_value(value)?),
+        v => bail!("unsupported store version {v}, expected at most {STORE_VERSION}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{hash, peer};
+
+    fn state() -> AgentState {
+        let mut state = AgentState {
+            token: Some(TokenType::Ckb),
+            ..Default::default()
+        };
+        for i in 1..3 {
+            state.pending.insert(
+                peer(i),
+                PendingChannel {
+                    opened_at: 10,
+                    temporary_channel_id: None,
+                    funds: 1000,
+                },
+            );
+        }
+        let channel_id = serde_json::from_value(serde_json::json!(hash(2))).expect("hash");
+        state.finish(&peer(1), AttemptOutcome::Opened { channel_id }, 20);
+        state.prune_flagged.insert(channel_id, 30);
+        state
+    }
+
+    fn to_value(state: &AgentState) -> serde_json::Value {
+        serde_json::to_value(state).expect("state")
+    }
+
+    #[test]
+    fn test_save_and_reopen() {
+        let dir = std::env::temp_dir().join(format!("store-{}", std::process::id()));
+        let store = Store::open(&dir).expect("open");
+        store.save("ckb", &state()).expect("save");
+
+        let reopened = Store::open(&dir).expect("reopen");
+        let (saved, other) = (reopened.load("ckb"), reopened.load("usdi"));
+        std::fs::remove_dir_all(&dir).expect("remove store");
+
+        assert_eq!(to_value(&saved), to_value(&state()));
+        assert!(other.pending.is_empty() && other.history.is_empty());
+    }
+
+    #[test]
+    fn test_migrate() {
+        // version 1 keys agents by position and has no token or opened channels
+        let mut v1 = to_value(&state());
+        v1.as_object_mut().expect("object").remove("token");
+        v1.as_object_mut().expect("object").remove("opened");
+        let value = serde_json::json!({
+            "version": 1,
+            "agents": { "agent-0": v1, "agent-1": AgentState::default() },
+        });
+        let store = Store {
+            path: None,
+            data: Arc::new(Mutex::new(migrate(value).expect("migrate"))),
+        };
+        store
+            .rename_positional(&["ckb".to_string()])
+            .expect("rename");
+
+        let migrated = store.load("ckb");
+        assert!(migrated.token.is_none());
+        assert_eq!(migrated.pending.len(), 1);
+        assert_eq!(migrated.history.len(), 1);
+        assert_eq!(migrated.opened.len(), 1);
+        assert_eq!(migrated.prune_flagged.len(), 1);
+        assert!(store.load("agent-0").pending.is_empty());
+
+        let value = serde_json::json!({ "version": STORE_VERSION + 1, "agents": {} });
+        assert!(migrate(value).is_err());
+    }
+}
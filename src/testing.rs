@@ -0,0 +1,177 @@
+//! Fixtures shared by tests
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use ckb_jsonrpc_types::Script;
+use fnn::{fiber::types::Pubkey, rpc::peer::PeerId};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    config::TokenType,
+    graph::Graph,
+    heuristics::{from_params, register_heuristic},
+    traits::{BoxFuture, Heuristic, HeuristicContext},
+    utils::unix_timestamp,
+};
+
+/// Capacity of fixture channels
+pub const CHAN_FUNDS: u128 = 1000;
+
+/// Compressed public keys of 1G ~ 8G on secp256k1, the first one is the autopilot node
+pub const PUBKEYS: [&str; 8] = [
+    "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+    "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+    "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+    "02e493dbf1c10d80f3581e4904930b1404cc6c13900ee0758474fa94abe8c4cd13",
+    "022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4",
+    "03fff97bd5755eeea420453a14355235d382f6472f8568a18b2f057a1460297556",
+    "025cbdf0646e5db4eaa398f365f2ea7a0e3d419b7e0330e39ce92bddedcac4f9bc",
+    "022f01e5e15cca351daff3843fb70f3c2f0a1bdd05e5af888a67784ef3e10a2a01",
+];
+
+pub fn pubkey(i: usize) -> Pubkey {
+    serde_json::from_value(json!(PUBKEYS[i])).expect("pubkey")
+}
+
+pub fn peer(i: usize) -> PeerId {
+    PeerId::from_public_key(&pubkey(i).into())
+}
+
+pub fn address(i: usize) -> String {
+    format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", 8228 + i, peer(i))
+}
+
+pub fn hash(n: usize) -> String {
+    format!("0x{n:064x}")
+}
+
+/// Node `i` announced just now
+pub fn node(i: usize) -> Value {
+    json!({
+        "node_name": format!("node-{i}"),
+        "addresses": [address(i)],
+        "node_id": PUBKEYS[i],
+        "timestamp": format!("{:#x}", unix_timestamp() * 1000),
+        "chain_hash": hash(0),
+        "auto_accept_min_ckb_funding_amount": "0x0",
+        "udt_cfg_infos": [],
+    })
+}
+
+/// CKB channel `index` between nodes `a` and `b`
+pub fn channel(index: usize, a: usize, b: usize) -> Value {
+    json!({
+        "channel_outpoint": format!("0x{index:064x}00000000"),
+        "node1": PUBKEYS[a],
+        "node2": PUBKEYS[b],
+        "created_timestamp": "0x0",
+        "capacity": format!("{CHAN_FUNDS:#x}"),
+        "chain_hash": hash(0),
+        "udt_type_script": null,
+    })
+}
+
+/// UDT token `n` whose type script has the code hash `hash(n)`
+pub fn udt(n: usize) -> TokenType {
+    serde_json::from_value(json!({
+        "type": "Udt",
+        "name": format!("udt-{n}"),
+        "script": { "code_hash": hash(n), "hash_type": "type", "args": "0x" },
+    }))
+    .expect("udt")
+}
+
+/// Channel `index` of `token` between nodes `a` and `b`
+pub fn token_channel(index: usize, a: usize, b: usize, token: &TokenType, capacity: u128) -> Value {
+    let mut channel = channel(index, a, b);
+    channel["udt_type_script"] = json!(token.script());
+    channel["capacity"] = json!(format!("{capacity:#x}"));
+    channel
+}
+
+/// Default funding lock of the autopilot node
+pub fn funding_lock() -> Script {
+    serde_json::from_value(json!({
+        "code_hash": hash(0),
+        "hash_type": "type",
+        "args": "0x",
+    }))
+    .expect("lock")
+}
+
+/// Node info of the autopilot node
+pub fn node_info() -> Value {
+    json!({
+        "version": "0.5.0",
+        "commit_hash": "",
+        "node_id": PUBKEYS[0],
+        "node_name": "node-0",
+        "addresses": [address(0)],
+        "chain_hash": hash(0),
+        "open_channel_auto_accept_min_ckb_funding_amount": "0x0",
+        "auto_accept_channel_ckb_funding_amount": "0x0",
+        "default_funding_lock_script": funding_lock(),
+        "tlc_expiry_delta": "0x0",
+        "tlc_min_value": "0x0",
+        "tlc_max_value": "0x0",
+        "tlc_fee_proportional_millionths": "0x0",
+        "channel_count": "0x0",
+        "pending_channel_count": "0x0",
+        "peers_count": "0x0",
+        "udt_cfg_infos": [],
+    })
+}
+
+/// Graph of fixture nodes and channels
+pub fn graph(nodes: Vec<Value>, channels: Vec<Value>) -> Graph {
+    Graph::build(
+        serde_json::from_value(Value::Array(nodes)).expect("nodes"),
+        serde_json::from_value(Value::Array(channels)).expect("channels"),
+    )
+}
+
+/// Heuristic context of the autopilot node with seed 0
+pub fn context() -> HeuristicContext {
+    HeuristicContext {
+        self_id: pubkey(0),
+        token: TokenType::Ckb,
+        local_peers: HashSet::default(),
+        seed: 0,
+        fixed_seed: None,
+    }
+}
+
+/// Heuristic giving fixture peers the scores in its params, registered as `Fixed`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FixedScores {
+    /// Index of the fixture peer and its score
+    pub scores: Vec<(usize, f64)>,
+}
+
+impl Heuristic for FixedScores {
+    fn get_node_scores<'a>(
+        &'a self,
+        _graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        _context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>> {
+        let scores = self
+            .scores
+            .iter()
+            .map(|(i, s)| (peer(*i), *s))
+            .filter(|(peer, _)| nodes.contains(peer))
+            .collect();
+        Box::pin(async move { Ok(scores) })
+    }
+}
+
+/// Register `FixedScores` as `Fixed`, registering it again is harmless
+pub fn register_fixed() {
+    register_heuristic("Fixed", from_params::<FixedScores>());
+}
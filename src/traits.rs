@@ -1,18 +1,52 @@
-use std::future::Future;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
 
 use anyhow::Result;
 use ckb_jsonrpc_types::Script;
 use fnn::{
-    fiber::types::Hash256,
+    fiber::{
+        serde_utils::U128Hex,
+        types::{Hash256, Pubkey},
+    },
     rpc::{
         channel::{Channel, OpenChannelParams},
         graph::{ChannelInfo, NodeInfo},
         info::NodeInfoResult,
-        peer::MultiAddr,
+        peer::{MultiAddr, PeerId},
     },
 };
 
-use crate::config::TokenType;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::{config::TokenType, graph::Graph};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Balance of a lock script
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Balance {
+    #[serde_as(as = "U128Hex")]
+    pub total: u128,
+    /// Number of counted cells, `None` if the source only reports the total
+    pub cell_count: Option<u64>,
+    /// Amount of the largest cell, `None` if the source only reports the total
+    #[serde_as(as = "Option<U128Hex>")]
+    pub largest_cell: Option<u128>,
+}
+
+impl Balance {
+    pub fn add_cell(&mut self, amount: u128) {
+        self.total = self.total.saturating_add(amount);
+        self.cell_count = Some(self.cell_count.unwrap_or_default() + 1);
+        self.largest_cell = Some(self.largest_cell.unwrap_or_default().max(amount));
+    }
+}
 
 /// Query source data
 pub trait GraphSource {
@@ -31,10 +65,45 @@ pub trait GraphSource {
         &self,
         params: OpenChannelParams,
     ) -> impl Future<Output = Result<Hash256>> + Send;
+    /// Abandon a channel which is not ready yet
+    fn abandon_channel(&self, channel_id: Hash256) -> impl Future<Output = Result<()>> + Send;
+    /// Close a ready channel cooperatively, node defaults are used for `None` arguments
+    fn shutdown_channel(
+        &self,
+        channel_id: Hash256,
+        close_script: Option<Script>,
+        fee_rate: Option<u64>,
+    ) -> impl Future<Output = Result<()>> + Send;
     /// Get Balance of a lock script
     fn get_balance(
         &self,
         lock: Script,
         token: TokenType,
-    ) -> impl Future<Output = Result<u128>> + Send;
+    ) -> impl Future<Output = Result<Balance>> + Send;
+}
+
+/// The agent scoring candidates
+#[derive(Debug, Clone)]
+pub struct HeuristicContext {
+    /// The id of the autopilot node
+    pub self_id: Pubkey,
+    pub token: TokenType,
+    /// Peers we already have channels with the agent's token
+    pub local_peers: HashSet<PeerId>,
+    /// Seed of the round, heuristics drawing random numbers seed from it
+    pub seed: u64,
+    /// `selection.seed` if configured, heuristics reusing results across rounds seed from it
+    /// or from the graph instead of the seed of the round
+    pub fixed_seed: Option<u64>,
+}
+
+/// Score candidates, register an implementation with `heuristics::register_heuristic`
+pub trait Heuristic: Send + Sync {
+    /// Score each candidate in `nodes` from 0.0 to 1.0, missing candidates score 0.0
+    fn get_node_scores<'a>(
+        &'a self,
+        graph: Arc<Graph>,
+        nodes: HashSet<PeerId>,
+        context: &'a HeuristicContext,
+    ) -> BoxFuture<'a, Result<HashMap<PeerId, f64>>>;
 }
@@ -1,21 +1,46 @@
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use fnn::rpc::peer::{MultiAddr, PeerId};
-use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    Rng,
+};
 
-pub fn choice_n<T: Clone>(items: Vec<(T, f64)>, n: usize) -> Vec<(T, f64)> {
-    // return all items if less than n
-    if items.len() < n {
+// TODO: Remove after upgrade ckb_json_type to the same version
+macro_rules! conv {
+    ( $x:expr ) => {{
+        let v = serde_json::to_value($x).expect("conv");
+        serde_json::from_value(v).expect("conv")
+    }};
+}
+pub(crate) use conv;
+
+/// Sample `n` items without replacement weighted by the second field, items weighing zero
+/// or less are never picked
+pub fn choice_n<T: Clone, R: Rng + ?Sized>(
+    items: Vec<(T, f64)>,
+    n: usize,
+    rng: &mut R,
+) -> Vec<(T, f64)> {
+    let items: Vec<(T, f64)> = items
+        .into_iter()
+        .filter(|(_, w)| w.is_finite() && *w > 0.0)
+        .collect();
+    // return all items if no more than n
+    if items.len() <= n {
         return items;
     }
 
-    let mut rng = rand::rng();
-    let mut dist = WeightedIndex::new(items.iter().map(|item| item.1)).unwrap();
-    let mut samples = Vec::default();
+    let mut dist = WeightedIndex::new(items.iter().map(|item| item.1)).expect("positive weights");
+    let mut samples = Vec::with_capacity(n);
     while samples.len() < n {
-        let i = dist.sample(&mut rng);
-        dist.update_weights(&[(i, &0.0)]).unwrap();
+        let i = dist.sample(rng);
         samples.push(items[i].clone());
+        // more than n items are positive, so one is left after the last pick
+        dist.update_weights(&[(i, &0.0)]).expect("positive weights");
     }
     samples
 }
@@ -27,3 +52,11 @@ pub fn get_peer_id_from_addr(addr: &MultiAddr) -> Option<PeerId> {
     let p2p_str = parts.get(index + 1)?;
     PeerId::from_str(p2p_str).ok()
 }
+
+/// Current unix timestamp in seconds
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}